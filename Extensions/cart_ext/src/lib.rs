@@ -1,10 +1,14 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder}, datum::TimestampWithTimeZone, default, error, log,
+    pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
+use marks::{notify, CartItem};
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -16,6 +20,8 @@ pgrx::pg_module_magic!();
 
 #[pg_extern]
 fn setup_cart() -> Result<(), spi::Error> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     let create_product_sql = r#"
         CREATE TABLE IF NOT EXISTS PRODUCTUPDATE (
             stream_id TEXT NOT NULL,
@@ -23,10 +29,15 @@ fn setup_cart() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             seller_id TEXT NOT NULL,
             mark_status TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
     Spi::run(create_product_sql)?;
+    marks::ensure_mark_columns("PRODUCTUPDATE")?;
 
     let create_price_sql = r#"
         CREATE TABLE IF NOT EXISTS PRICEUPDATE (
@@ -35,10 +46,15 @@ fn setup_cart() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             seller_id TEXT NOT NULL,
             mark_status TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
     Spi::run(create_price_sql)?;
+    marks::ensure_mark_columns("PRICEUPDATE")?;
 
     let create_checkout_sql = r#"
         CREATE TABLE IF NOT EXISTS CHECKOUT (
@@ -47,10 +63,46 @@ fn setup_cart() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             customer_id TEXT NOT NULL,
             mark_status TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
     Spi::run(create_checkout_sql)?;
+    marks::ensure_mark_columns("CHECKOUT")?;
+
+    let create_voucher_sql = r#"
+        CREATE TABLE IF NOT EXISTS CART_VOUCHERS (
+            code TEXT PRIMARY KEY,
+            kind TEXT NOT NULL CHECK (kind IN ('fixed', 'percentage')),
+            amount REAL NOT NULL CHECK (amount >= 0),
+            seller_id INT,
+            min_basket REAL NOT NULL DEFAULT 0,
+            expires_at TIMESTAMPTZ,
+            usage_limit INT,
+            usage_count INT NOT NULL DEFAULT 0,
+            active BOOLEAN NOT NULL DEFAULT TRUE
+        );
+        CREATE TABLE IF NOT EXISTS CART_VOUCHER_APPLICATIONS (
+            customer_id INT NOT NULL,
+            code TEXT NOT NULL REFERENCES CART_VOUCHERS (code) ON DELETE CASCADE,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            PRIMARY KEY (customer_id, code)
+        );
+        CREATE TABLE IF NOT EXISTS CART_VOUCHER_REDEMPTIONS (
+            code TEXT NOT NULL,
+            customer_id INT NOT NULL,
+            instance_id TEXT NOT NULL,
+            discount REAL NOT NULL,
+            redeemed_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+    "#;
+    Spi::run(create_voucher_sql)?;
+    Spi::run(freight::SETUP_SQL)?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
     Ok(())
 }
 
@@ -61,15 +113,16 @@ fn setup_cart() -> Result<(), spi::Error> {
 #[pg_extern]
 fn cart_add_checkout_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    customer_id: &str, mark_status: &str, db: &str
+    customer_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        customer_id.into(), mark_status.into(), db.into(),
+        customer_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
@@ -77,15 +130,16 @@ fn cart_add_checkout_transaction_mark(
 #[pg_extern]
 fn cart_add_product_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    seller_id: &str, mark_status: &str, db: &str
+    seller_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO PRODUCTUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO PRODUCTUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        seller_id.into(), mark_status.into(), db.into(),
+        seller_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
@@ -93,19 +147,44 @@ fn cart_add_product_transaction_mark(
 #[pg_extern]
 fn cart_add_price_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    seller_id: &str, mark_status: &str, db: &str
+    seller_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO PRICEUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO PRICEUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        seller_id.into(), mark_status.into(), db.into(),
+        seller_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
 
+static MARK_TABLES: [&str; 3] = ["CHECKOUT", "PRICEUPDATE", "PRODUCTUPDATE"];
+
+#[pg_extern]
+fn cart_purge_marks(
+    before: TimestampWithTimeZone, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("cart"), Some(before), None, archive)?;
+    }
+    Ok(purged)
+}
+
+#[pg_extern]
+fn cart_purge_run_marks(
+    run_id: i64, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("cart"), None, Some(run_id), archive)?;
+    }
+    Ok(purged)
+}
+
 ////////////////////////////////////////
 // 3. Background Workers (BGWs)
 ////////////////////////////////////////
@@ -120,20 +199,23 @@ static CONN_INFO: &str =
 
 const BGW_ID_OFFSET: i32 = 5;  // Ensures unique BGW IDs (5-10)
 
+/// Starts the listeners that apply price and product changes to the replica.
+/// With `forward`, each event is also relayed to CartMS on its out channel.
 #[pg_extern]
-fn cart_listen_to_changes() -> Result<(), String> {
+fn cart_listen_to_changes(forward: default!(bool, false)) -> Result<(), String> {
     for (i, _) in CHANNELS.iter().enumerate() {
-        spawn_listener(i as i32 + BGW_ID_OFFSET)?;
+        spawn_listener(i as i32 + BGW_ID_OFFSET, forward)?;
     }
     Ok(())
 }
 
-fn spawn_listener(id: i32) -> Result<(), String> {
+fn spawn_listener(id: i32, forward: bool) -> Result<(), String> {
     BackgroundWorkerBuilder::new("cart_listener")
         .set_library("cart_ext")
         .set_function("listen_bgworker")
         .enable_spi_access()
         .set_argument(id.into_datum())
+        .set_extra(if forward { "forward" } else { "" })
         .load_dynamic();
     Ok(())
 }
@@ -169,7 +251,12 @@ pub extern "C" fn listen_bgworker(arg: pg_sys::Datum) {
 
 fn run_bgworker(id: i32) {
     let (in_channel, out_channel) = CHANNELS[(id - BGW_ID_OFFSET) as usize];
-    log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    let forward = BackgroundWorker::get_extra() == "forward";
+    if forward {
+        log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    } else {
+        log!("BGW {id}: Starting, listening on `{in_channel}`");
+    }
 
     loop {
         pgrx::check_for_interrupts!();
@@ -194,14 +281,19 @@ fn run_bgworker(id: i32) {
                             unsafe { pg_sys::StartTransactionCommand(); }
                             unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()); }
 
-                            let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
-                            let spi_result = Spi::run_with_args(&notify_sql, &[payload.clone().into()]);
+                            let spi_result = apply_replica_change(in_channel, &payload).and_then(|_| {
+                                if !forward {
+                                    return Ok(());
+                                }
+                                let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
+                                Spi::run_with_args(&notify_sql, &[payload.clone().into()])
+                            });
 
                             unsafe { pg_sys::PopActiveSnapshot(); }
                             unsafe { pg_sys::CommitTransactionCommand(); }
 
                             if let Err(e) = spi_result {
-                                log!("BGW {id}: SPI error while NOTIFY: {e}");
+                                log!("BGW {id}: SPI error while applying `{in_channel}` event: {e}");
                             }
                         }
                         Ok(None) => break,
@@ -220,3 +312,926 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 4. Checkout
+////////////////////////////////////////
+
+const CHECKOUT_STREAM_ID: &str = "TransactionMark_CUSTOMER_SESSION";
+
+// ItemStatus ordinal, as serialized by the C# services
+const ITEM_PRICE_DIVERGENCE: i32 = 2;
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProductStatus {
+    id: i32,
+    status: i32,
+    unit_price: f32,
+    old_unit_price: f32,
+    qty_available: i32,
+}
+
+fn now_json() -> Result<Value, spi::Error> {
+    Ok(Spi::get_one::<JsonB>("SELECT to_jsonb(now())")?.map(|ts| ts.0).unwrap_or(Value::Null))
+}
+
+fn reject_checkout(
+    customer_id: i32, instance_id: &str, reason: &str, divergences: Vec<ProductStatus>
+) -> Result<JsonB, spi::Error> {
+    cart_add_checkout_transaction_mark(
+        CHECKOUT_STREAM_ID, instance_id, "CUSTOMER_SESSION",
+        &customer_id.to_string(), "NOT_ACCEPTED", "cart", None,
+    )?;
+    Ok(JsonB(json!({
+        "instanceId": instance_id,
+        "status": "not_accepted",
+        "reason": reason,
+        "divergences": divergences,
+    })))
+}
+
+/// Validates the customer's cart against the product replica, publishes the
+/// ReserveStock event on `checkout` and clears the cart, all in the caller's
+/// transaction. Items whose replica has the same version but another price are
+/// dropped as divergent, as CartService does. Freight is quoted from the
+/// freight rates, and the cart's vouchers are folded into each item's Voucher
+/// and redeemed.
+///
+/// Only a rejected checkout records a mark (NOT_ACCEPTED). An accepted one is
+/// deliberately left unmarked, as in CartService: the mark tables fold a
+/// transaction to its latest mark, so a cart SUCCESS would report checkouts
+/// still in flight as completed. Shipment's SUCCESS, or the first failure
+/// downstream, marks the outcome.
+#[pg_extern]
+fn cart_checkout(customer_id: i32, checkout: JsonB) -> Result<JsonB, spi::Error> {
+    let customer_checkout = checkout.0;
+    let instance_id = customer_checkout["instanceId"].as_str().unwrap_or_default().to_string();
+
+    if customer_checkout["CustomerId"].as_i64() != Some(customer_id as i64) {
+        return reject_checkout(customer_id, &instance_id, "checkout payload does not match customer", vec![]);
+    }
+
+    let cart_status = Spi::get_one_with_args::<String>(
+        "SELECT status FROM cart.carts WHERE customer_id = $1 FOR UPDATE",
+        &[customer_id.into()],
+    );
+    match cart_status {
+        Err(spi::Error::InvalidPosition) => {
+            return reject_checkout(customer_id, &instance_id, "cart not found", vec![]);
+        }
+        Ok(Some(status)) if status == "CHECKOUT_SENT" => {
+            return reject_checkout(customer_id, &instance_id, "cart already submitted for checkout", vec![]);
+        }
+        other => {
+            other?;
+        }
+    }
+
+    let items_sql = r#"
+        SELECT i.seller_id, i.product_id, i.product_name, i.unit_price, i.freight_value,
+               i.quantity, i.voucher, i.version,
+               r.price AS replica_price, r.version AS replica_version
+        FROM cart.cart_items i
+        LEFT JOIN cart.replica_products r
+               ON r.seller_id = i.seller_id AND r.product_id = i.product_id
+        WHERE i.customer_id = $1
+        ORDER BY i.seller_id, i.product_id;
+    "#;
+    let (mut items, divergences) = Spi::connect(|client| {
+        let mut items = Vec::new();
+        let mut divergences = Vec::new();
+        for row in client.select(items_sql, None, &[customer_id.into()])? {
+            let item = CartItem {
+                seller_id: row.get_by_name("seller_id")?.unwrap_or_default(),
+                product_id: row.get_by_name("product_id")?.unwrap_or_default(),
+                product_name: row.get_by_name("product_name")?.unwrap_or_default(),
+                unit_price: row.get_by_name("unit_price")?.unwrap_or_default(),
+                freight_value: row.get_by_name("freight_value")?.unwrap_or_default(),
+                quantity: row.get_by_name("quantity")?.unwrap_or_default(),
+                voucher: row.get_by_name("voucher")?.unwrap_or_default(),
+                version: row.get_by_name("version")?.unwrap_or_default(),
+            };
+            let replica_price: Option<f32> = row.get_by_name("replica_price")?;
+            let replica_version: Option<String> = row.get_by_name("replica_version")?;
+
+            match (replica_price, replica_version) {
+                (Some(price), Some(version)) if version == item.version && price != item.unit_price => {
+                    divergences.push(ProductStatus {
+                        id: item.product_id,
+                        status: ITEM_PRICE_DIVERGENCE,
+                        unit_price: price,
+                        old_unit_price: item.unit_price,
+                        qty_available: 0,
+                    });
+                }
+                _ => items.push(item),
+            }
+        }
+        Ok::<_, spi::Error>((items, divergences))
+    })?;
+
+    if items.is_empty() {
+        return reject_checkout(customer_id, &instance_id, "cart has no items to be submitted", divergences);
+    }
+
+    quote_freight(&mut items)?;
+    let pricing = price_items(customer_id, &mut items, true)?;
+    redeem_vouchers(customer_id, &instance_id, &pricing)?;
+
+    notify("checkout", &json!({
+        "timestamp": now_json()?,
+        "customerCheckout": customer_checkout,
+        "items": items,
+        "instanceId": instance_id,
+    }))?;
+
+    Spi::run_with_args("DELETE FROM cart.cart_items WHERE customer_id = $1", &[customer_id.into()])?;
+    Spi::run_with_args(
+        "UPDATE cart.carts SET status = 'OPEN', updated_at = now() WHERE customer_id = $1",
+        &[customer_id.into()],
+    )?;
+
+    Ok(JsonB(json!({
+        "instanceId": instance_id,
+        "status": "accepted",
+        "items": items,
+        "divergences": divergences,
+        "pricing": pricing.summary(&items),
+    })))
+}
+
+////////////////////////////////////////
+// 5. Product Replica
+////////////////////////////////////////
+
+const PRICE_UPDATE_STREAM_ID: &str = "TransactionMark_PRICE_UPDATE";
+const PRODUCT_UPDATE_STREAM_ID: &str = "TransactionMark_UPDATE_PRODUCT";
+
+#[derive(Deserialize)]
+struct PriceUpdated {
+    seller_id: i32,
+    product_id: i32,
+    price: f32,
+    version: String,
+    #[serde(rename = "instanceId")]
+    instance_id: String,
+}
+
+#[derive(Deserialize)]
+struct ProductUpdated {
+    seller_id: i32,
+    product_id: i32,
+    name: String,
+    price: f32,
+    version: String,
+}
+
+// Versions are the driver's transaction ids; a numerically lower one arrived out of order
+const STALE_VERSION_SQL: &str = r#"
+    replica_products.version ~ '^[0-9]+$' AND EXCLUDED.version ~ '^[0-9]+$'
+    AND replica_products.version::NUMERIC > EXCLUDED.version::NUMERIC
+"#;
+
+fn apply_replica_change(channel: &str, payload: &str) -> Result<(), spi::Error> {
+    match channel {
+        "price_changes" => match serde_json::from_str::<PriceUpdated>(payload) {
+            Ok(price_updated) => apply_price_update(&price_updated),
+            Err(e) => {
+                log!("cart_ext: malformed PriceUpdated payload: {e}");
+                Ok(())
+            }
+        },
+        "product_changes" => match serde_json::from_str::<ProductUpdated>(payload) {
+            Ok(product_updated) => apply_product_update(&product_updated),
+            Err(e) => {
+                log!("cart_ext: malformed ProductUpdated payload: {e}");
+                Ok(())
+            }
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Mirrors CartService.ProcessPriceUpdate: the replica only takes the new
+/// price when it holds the same version, while cart items of that version are
+/// repriced in any case. Their voucher is left as is, since CartService adds
+/// the difference only after overwriting unit_price, i.e. adds nothing.
+/// Records the PRICE_UPDATE SUCCESS mark.
+fn apply_price_update(price_updated: &PriceUpdated) -> Result<(), spi::Error> {
+    Spi::run_with_args(r#"
+        UPDATE cart.replica_products SET price = $3, updated_at = now()
+        WHERE seller_id = $1 AND product_id = $2 AND version = $4;
+    "#, &[
+        price_updated.seller_id.into(), price_updated.product_id.into(),
+        price_updated.price.into(), price_updated.version.clone().into(),
+    ])?;
+
+    Spi::run_with_args(r#"
+        UPDATE cart.cart_items SET unit_price = $3
+        WHERE seller_id = $1 AND product_id = $2 AND version = $4;
+    "#, &[
+        price_updated.seller_id.into(), price_updated.product_id.into(),
+        price_updated.price.into(), price_updated.version.clone().into(),
+    ])?;
+
+    cart_add_price_transaction_mark(
+        PRICE_UPDATE_STREAM_ID, &price_updated.instance_id, "PRICE_UPDATE",
+        &price_updated.seller_id.to_string(), "SUCCESS", "cart", None,
+    )
+}
+
+/// Mirrors CartService.ProcessProductUpdated, upserting the replica unless it
+/// already holds a newer version, and records cart's UPDATE_PRODUCT SUCCESS
+/// mark keyed by the version, as stock does. A stale update is still marked
+/// SUCCESS: the replica is already past it.
+fn apply_product_update(product_updated: &ProductUpdated) -> Result<(), spi::Error> {
+    let upsert_sql = format!(r#"
+        INSERT INTO cart.replica_products (seller_id, product_id, name, price, version, active, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, true, now(), now())
+        ON CONFLICT (seller_id, product_id) DO UPDATE
+        SET name = EXCLUDED.name, price = EXCLUDED.price, version = EXCLUDED.version,
+            active = true, updated_at = now()
+        WHERE NOT ({STALE_VERSION_SQL})
+        RETURNING version;
+    "#);
+    let applied = Spi::connect_mut(|client| {
+        let rows = client.update(&upsert_sql, None, &[
+            product_updated.seller_id.into(), product_updated.product_id.into(),
+            product_updated.name.clone().into(), product_updated.price.into(),
+            product_updated.version.clone().into(),
+        ])?;
+        Ok::<_, spi::Error>(rows.len() > 0)
+    })?;
+
+    if !applied {
+        log!(
+            "cart_ext: dropped stale update of product {}/{} to version {}",
+            product_updated.seller_id, product_updated.product_id, product_updated.version
+        );
+    }
+    cart_add_product_transaction_mark(
+        PRODUCT_UPDATE_STREAM_ID, &product_updated.version, "UPDATE_PRODUCT",
+        &product_updated.seller_id.to_string(), "SUCCESS", "cart", None,
+    )
+}
+
+////////////////////////////////////////
+// 6. Abandoned-Cart Expiry
+////////////////////////////////////////
+
+const DEFAULT_SCAN_INTERVAL_SECS: f64 = 60.0;
+
+/// Creates (or updates) the expiry settings read by the expiry worker on every
+/// scan, and records when each customer last added a cart item in
+/// CART_ITEM_ACTIVITY, since CartMS does not touch the cart when an item is
+/// added. Carts that already have items count as active from now on.
+/// Requires CartMS' tables.
+#[pg_extern]
+fn setup_cart_expiry(
+    ttl_secs: default!(f64, 3600.0),
+    scan_interval_secs: default!(f64, 60.0),
+    batch_size: default!(i32, 100),
+) -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS CART_EXPIRY_SETTINGS (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            ttl_secs FLOAT8 NOT NULL,
+            scan_interval_secs FLOAT8 NOT NULL,
+            batch_size INT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS CART_METRICS (
+            metric TEXT PRIMARY KEY,
+            value BIGINT NOT NULL DEFAULT 0,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+        CREATE TABLE IF NOT EXISTS CART_ITEM_ACTIVITY (
+            customer_id INT PRIMARY KEY,
+            last_added_at TIMESTAMPTZ NOT NULL
+        );
+
+        CREATE OR REPLACE FUNCTION cart_record_item_activity() RETURNS TRIGGER
+        LANGUAGE plpgsql AS $$
+        BEGIN
+            INSERT INTO CART_ITEM_ACTIVITY (customer_id, last_added_at)
+            VALUES (NEW.customer_id, now())
+            ON CONFLICT (customer_id) DO UPDATE SET last_added_at = EXCLUDED.last_added_at;
+            RETURN NULL;
+        END;
+        $$;
+
+        LOCK TABLE cart.cart_items IN SHARE ROW EXCLUSIVE MODE;
+        INSERT INTO CART_ITEM_ACTIVITY (customer_id, last_added_at)
+        SELECT DISTINCT customer_id, now() FROM cart.cart_items
+        ON CONFLICT (customer_id) DO NOTHING;
+
+        DROP TRIGGER IF EXISTS cart_item_activity_recorder ON cart.cart_items;
+        CREATE TRIGGER cart_item_activity_recorder
+        AFTER INSERT ON cart.cart_items
+        FOR EACH ROW
+        EXECUTE FUNCTION cart_record_item_activity();
+    "#)?;
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO CART_EXPIRY_SETTINGS (id, ttl_secs, scan_interval_secs, batch_size)
+        VALUES (TRUE, $1, $2, $3)
+        ON CONFLICT (id) DO UPDATE SET
+            ttl_secs = EXCLUDED.ttl_secs,
+            scan_interval_secs = EXCLUDED.scan_interval_secs,
+            batch_size = EXCLUDED.batch_size;
+        "#,
+        &[ttl_secs.into(), scan_interval_secs.into(), batch_size.into()],
+    )?;
+    Ok(())
+}
+
+/// Requires `setup_cart_expiry()`.
+#[pg_extern]
+fn start_cart_expiry_worker() -> Result<(), String> {
+    BackgroundWorkerBuilder::new("cart_expiry_worker")
+        .set_library("cart_ext")
+        .set_function("expiry_bgworker")
+        .enable_spi_access()
+        .load_dynamic();
+    Ok(())
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn expiry_bgworker(_arg: pg_sys::Datum) {
+    register_pg_guard_panic_hook();
+
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnection(
+            b"postgres\0".as_ptr() as *const i8,
+            b"ucloud\0".as_ptr() as *const i8,
+            0,
+        );
+    }
+
+    marks::run_periodic("Expiry BGW", "abandoned cart(s) expired", DEFAULT_SCAN_INTERVAL_SECS, expire_abandoned_carts);
+}
+
+/// Runs one expiry scan in the caller's transaction and returns the number of
+/// carts expired.
+#[pg_extern]
+fn cart_expire_abandoned() -> Result<i64, spi::Error> {
+    expire_abandoned_carts().map(|(expired, _)| expired)
+}
+
+/// Empties every open cart idle beyond the TTL, as CartService.Seal does, and
+/// publishes `cart_abandoned` with the dropped items. Carts being checked out
+/// hold their row lock and are skipped until the next scan. Returns the
+/// number expired and the configured scan interval.
+fn expire_abandoned_carts() -> Result<(i64, f64), spi::Error> {
+    let (ttl_secs, scan_interval_secs, batch_size) = Spi::get_three::<f64, f64, i32>(
+        "SELECT ttl_secs, scan_interval_secs, batch_size FROM CART_EXPIRY_SETTINGS",
+    )?;
+    let ttl_secs = ttl_secs.unwrap_or(3600.0);
+    let scan_interval_secs = scan_interval_secs.unwrap_or(DEFAULT_SCAN_INTERVAL_SECS);
+
+    let idle_sql = r#"
+        SELECT c.customer_id, to_jsonb(greatest(c.updated_at, a.last_added_at)) AS last_activity
+        FROM cart.carts c
+        JOIN CART_ITEM_ACTIVITY a ON a.customer_id = c.customer_id
+        WHERE c.status = 'OPEN'
+          AND EXISTS (SELECT 1 FROM cart.cart_items i WHERE i.customer_id = c.customer_id)
+          AND greatest(c.updated_at, a.last_added_at) < now() - make_interval(secs => $1)
+        ORDER BY c.customer_id
+        LIMIT $2
+        FOR UPDATE OF c SKIP LOCKED;
+    "#;
+    let idle = Spi::connect_mut(|client| {
+        let mut idle = Vec::new();
+        for row in client.update(idle_sql, None, &[ttl_secs.into(), batch_size.unwrap_or(100).into()])? {
+            idle.push((
+                row.get_by_name::<i32, _>("customer_id")?.unwrap_or_default(),
+                row.get_by_name::<JsonB, _>("last_activity")?.map(|ts| ts.0).unwrap_or(Value::Null),
+            ));
+        }
+        Ok::<_, spi::Error>(idle)
+    })?;
+    if idle.is_empty() {
+        return Ok((0, scan_interval_secs));
+    }
+
+    let expire_sql = r#"
+        DELETE FROM cart.cart_items WHERE customer_id = $1
+        RETURNING seller_id, product_id, product_name, unit_price, freight_value, quantity, voucher, version;
+    "#;
+    let timestamp = now_json()?;
+    let mut expired_items = 0;
+    for (customer_id, last_activity) in &idle {
+        let items = Spi::connect_mut(|client| {
+            let mut items = Vec::new();
+            for row in client.update(expire_sql, None, &[(*customer_id).into()])? {
+                items.push(CartItem {
+                    seller_id: row.get_by_name("seller_id")?.unwrap_or_default(),
+                    product_id: row.get_by_name("product_id")?.unwrap_or_default(),
+                    product_name: row.get_by_name("product_name")?.unwrap_or_default(),
+                    unit_price: row.get_by_name("unit_price")?.unwrap_or_default(),
+                    freight_value: row.get_by_name("freight_value")?.unwrap_or_default(),
+                    quantity: row.get_by_name("quantity")?.unwrap_or_default(),
+                    voucher: row.get_by_name("voucher")?.unwrap_or_default(),
+                    version: row.get_by_name("version")?.unwrap_or_default(),
+                });
+            }
+            Ok::<_, spi::Error>(items)
+        })?;
+        Spi::run_with_args(
+            "UPDATE cart.carts SET updated_at = now() WHERE customer_id = $1",
+            &[(*customer_id).into()],
+        )?;
+        Spi::run_with_args(
+            "DELETE FROM CART_VOUCHER_APPLICATIONS WHERE customer_id = $1",
+            &[(*customer_id).into()],
+        )?;
+        expired_items += items.len() as i64;
+
+        notify("cart_abandoned", &json!({
+            "timestamp": timestamp,
+            "customerId": customer_id,
+            "items": items,
+            "lastActivity": last_activity,
+        }))?;
+    }
+
+    let expired = idle.len() as i64;
+    Spi::run_with_args(
+        r#"
+        INSERT INTO CART_METRICS (metric, value)
+        VALUES ('carts_abandoned', $1), ('cart_items_expired', $2)
+        ON CONFLICT (metric) DO UPDATE SET
+            value = CART_METRICS.value + EXCLUDED.value,
+            updated_at = clock_timestamp();
+        "#,
+        &[expired.into(), expired_items.into()],
+    )?;
+
+    Ok((expired, scan_interval_secs))
+}
+
+////////////////////////////////////////
+// 7. Vouchers
+////////////////////////////////////////
+
+/// Creates or replaces a voucher. `fixed` takes `amount` off the basket,
+/// `percentage` takes `amount` percent; with `seller_id` only that seller's
+/// items count as the basket.
+#[pg_extern]
+fn cart_define_voucher(
+    code: &str,
+    kind: &str,
+    amount: f32,
+    seller_id: default!(Option<i32>, "NULL"),
+    min_basket: default!(f32, 0.0),
+    expires_at: default!(Option<TimestampWithTimeZone>, "NULL"),
+    usage_limit: default!(Option<i32>, "NULL"),
+) -> Result<(), spi::Error> {
+    if kind != "fixed" && kind != "percentage" {
+        error!("cart_define_voucher: unknown voucher kind `{kind}`");
+    }
+    Spi::run_with_args(
+        r#"
+        INSERT INTO CART_VOUCHERS (code, kind, amount, seller_id, min_basket, expires_at, usage_limit)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (code) DO UPDATE SET
+            kind = EXCLUDED.kind, amount = EXCLUDED.amount, seller_id = EXCLUDED.seller_id,
+            min_basket = EXCLUDED.min_basket, expires_at = EXCLUDED.expires_at,
+            usage_limit = EXCLUDED.usage_limit, active = TRUE;
+        "#,
+        &[
+            code.into(), kind.into(), amount.into(), seller_id.into(),
+            min_basket.into(), expires_at.into(), usage_limit.into(),
+        ],
+    )
+}
+
+/// Attaches a voucher to the customer's cart. Whether it applies is decided
+/// when the cart is priced.
+#[pg_extern]
+fn cart_apply_voucher(customer_id: i32, code: &str) -> Result<(), spi::Error> {
+    let known = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM CART_VOUCHERS WHERE code = $1 AND active)",
+        &[code.into()],
+    )?;
+    if known != Some(true) {
+        error!("cart_apply_voucher: unknown voucher `{code}`");
+    }
+    Spi::run_with_args(
+        "INSERT INTO CART_VOUCHER_APPLICATIONS (customer_id, code) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        &[customer_id.into(), code.into()],
+    )
+}
+
+#[pg_extern]
+fn cart_remove_voucher(customer_id: i32, code: &str) -> Result<(), spi::Error> {
+    Spi::run_with_args(
+        "DELETE FROM CART_VOUCHER_APPLICATIONS WHERE customer_id = $1 AND code = $2",
+        &[customer_id.into(), code.into()],
+    )
+}
+
+/// A voucher attached to a cart, as read for pricing.
+struct AppliedVoucher {
+    applied_at: f64,
+    code: String,
+    kind: String,
+    amount: f32,
+    seller_id: Option<i32>,
+    min_basket: f32,
+    expired: bool,
+    exhausted: bool,
+}
+
+struct Pricing {
+    applied: Vec<(String, f32)>,
+    rejected: Vec<(String, &'static str)>,
+    // discount the vouchers added to each item, in item order
+    discounts: Vec<f32>,
+}
+
+impl Pricing {
+    fn summary(&self, items: &[CartItem]) -> Value {
+        let subtotal: f32 = items.iter().map(|i| i.unit_price * i.quantity as f32).sum();
+        let voucher: f32 = items.iter().map(|i| i.voucher).sum();
+        let freight: f32 = items.iter().map(|i| i.freight_value).sum();
+        json!({
+            "items": items.iter().zip(&self.discounts).map(|(item, discount)| json!({
+                "SellerId": item.seller_id,
+                "ProductId": item.product_id,
+                "Quantity": item.quantity,
+                "UnitPrice": item.unit_price,
+                "FreightValue": item.freight_value,
+                "Voucher": item.voucher,
+                "VoucherDiscount": discount,
+                "Total": item.unit_price * item.quantity as f32 - item.voucher,
+            })).collect::<Vec<_>>(),
+            "subtotal": subtotal,
+            "discount": voucher,
+            "freight": freight,
+            "total": subtotal - voucher + freight,
+            "vouchers": self.applied.iter().map(|(code, discount)| json!({ "code": code, "discount": discount }))
+                .collect::<Vec<_>>(),
+            "rejected": self.rejected.iter().map(|(code, reason)| json!({ "code": code, "reason": reason }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Applies the customer's vouchers, in the order they were attached, on top of
+/// the Voucher each item already carries. A voucher's discount is spread over
+/// the items of its basket in proportion to what is left to pay on each, so no
+/// item goes below zero. With `lock`, the vouchers stay locked until commit.
+fn price_items(customer_id: i32, items: &mut [CartItem], lock: bool) -> Result<Pricing, spi::Error> {
+    let vouchers_sql = format!(r#"
+        SELECT extract(epoch FROM a.applied_at)::FLOAT8 AS applied_at,
+               v.code, v.kind, v.amount, v.seller_id, v.min_basket,
+               NOT v.active OR (v.expires_at IS NOT NULL AND v.expires_at <= now()) AS expired,
+               v.usage_limit IS NOT NULL AND v.usage_count >= v.usage_limit AS exhausted
+        FROM CART_VOUCHER_APPLICATIONS a
+        JOIN CART_VOUCHERS v ON v.code = a.code
+        WHERE a.customer_id = $1
+        ORDER BY v.code
+        {};
+    "#, if lock { "FOR UPDATE OF v" } else { "" });
+    // Vouchers are locked in code order, as concurrent checkouts may share
+    // them, and applied in the order the customer added them
+    let mut vouchers = Spi::connect_mut(|client| {
+        let mut vouchers = Vec::new();
+        for row in client.update(&vouchers_sql, None, &[customer_id.into()])? {
+            vouchers.push(AppliedVoucher {
+                applied_at: row.get_by_name("applied_at")?.unwrap_or_default(),
+                code: row.get_by_name("code")?.unwrap_or_default(),
+                kind: row.get_by_name("kind")?.unwrap_or_default(),
+                amount: row.get_by_name("amount")?.unwrap_or_default(),
+                seller_id: row.get_by_name("seller_id")?,
+                min_basket: row.get_by_name("min_basket")?.unwrap_or_default(),
+                expired: row.get_by_name("expired")?.unwrap_or_default(),
+                exhausted: row.get_by_name("exhausted")?.unwrap_or_default(),
+            });
+        }
+        Ok::<_, spi::Error>(vouchers)
+    })?;
+    vouchers.sort_by(|a, b| a.applied_at.total_cmp(&b.applied_at).then_with(|| a.code.cmp(&b.code)));
+
+    let mut pricing = Pricing { applied: Vec::new(), rejected: Vec::new(), discounts: vec![0.0; items.len()] };
+    for AppliedVoucher { code, kind, amount, seller_id, min_basket, expired, exhausted, .. } in vouchers {
+        let in_basket = |item: &CartItem| seller_id.is_none() || seller_id == Some(item.seller_id);
+        let basket: f32 = items.iter().filter(|&i| in_basket(i)).map(|i| i.unit_price * i.quantity as f32).sum();
+        let remaining: f32 = items.iter().filter(|&i| in_basket(i))
+            .map(|i| (i.unit_price * i.quantity as f32 - i.voucher).max(0.0))
+            .sum();
+
+        let rejection = if expired {
+            Some("expired")
+        } else if exhausted {
+            Some("usage limit reached")
+        } else if basket == 0.0 {
+            Some("no eligible items")
+        } else if basket < min_basket {
+            Some("below minimum basket")
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            pricing.rejected.push((code, reason));
+            continue;
+        }
+
+        let discount = match kind.as_str() {
+            "percentage" => remaining * amount.min(100.0) / 100.0,
+            _ => amount.min(remaining),
+        };
+        if remaining > 0.0 {
+            for (item, item_discount) in items.iter_mut().zip(pricing.discounts.iter_mut()) {
+                if !in_basket(item) {
+                    continue;
+                }
+                let share = discount * (item.unit_price * item.quantity as f32 - item.voucher).max(0.0) / remaining;
+                item.voucher += share;
+                *item_discount += share;
+            }
+        }
+        pricing.applied.push((code, discount));
+    }
+    Ok(pricing)
+}
+
+/// Counts the applied vouchers as used by `instance_id` and detaches all of
+/// the customer's vouchers, as the cart they priced is being emptied.
+fn redeem_vouchers(customer_id: i32, instance_id: &str, pricing: &Pricing) -> Result<(), spi::Error> {
+    for (code, discount) in &pricing.applied {
+        Spi::run_with_args(
+            "UPDATE CART_VOUCHERS SET usage_count = usage_count + 1 WHERE code = $1",
+            &[code.as_str().into()],
+        )?;
+        Spi::run_with_args(
+            r#"
+            INSERT INTO CART_VOUCHER_REDEMPTIONS (code, customer_id, instance_id, discount)
+            VALUES ($1, $2, $3, $4);
+            "#,
+            &[code.as_str().into(), customer_id.into(), instance_id.into(), (*discount).into()],
+        )?;
+    }
+    Spi::run_with_args(
+        "DELETE FROM CART_VOUCHER_APPLICATIONS WHERE customer_id = $1",
+        &[customer_id.into()],
+    )
+}
+
+/// Prices the customer's cart without changing it: per item amounts with the
+/// freight quoted and the vouchers applied, and the basket totals.
+#[pg_extern]
+fn cart_price(customer_id: i32) -> Result<JsonB, spi::Error> {
+    let items_sql = r#"
+        SELECT seller_id, product_id, product_name, unit_price, freight_value, quantity, voucher, version
+        FROM cart.cart_items
+        WHERE customer_id = $1
+        ORDER BY seller_id, product_id;
+    "#;
+    let mut items = Spi::connect(|client| {
+        let mut items = Vec::new();
+        for row in client.select(items_sql, None, &[customer_id.into()])? {
+            items.push(CartItem {
+                seller_id: row.get_by_name("seller_id")?.unwrap_or_default(),
+                product_id: row.get_by_name("product_id")?.unwrap_or_default(),
+                product_name: row.get_by_name("product_name")?.unwrap_or_default(),
+                unit_price: row.get_by_name("unit_price")?.unwrap_or_default(),
+                freight_value: row.get_by_name("freight_value")?.unwrap_or_default(),
+                quantity: row.get_by_name("quantity")?.unwrap_or_default(),
+                voucher: row.get_by_name("voucher")?.unwrap_or_default(),
+                version: row.get_by_name("version")?.unwrap_or_default(),
+            });
+        }
+        Ok::<_, spi::Error>(items)
+    })?;
+
+    quote_freight(&mut items)?;
+    let pricing = price_items(customer_id, &mut items, false)?;
+    let mut summary = pricing.summary(&items);
+    summary["customerId"] = json!(customer_id);
+    Ok(JsonB(summary))
+}
+
+////////////////////////////////////////
+// 8. Freight
+////////////////////////////////////////
+
+/// Replaces each item's FreightValue with the freight calculator's quote, so
+/// the ReserveStock items, and the orders and packages made from them, carry
+/// the configured freight. Items of sellers without a rate (and no default
+/// rate) keep the freight they were added with.
+fn quote_freight(items: &mut [CartItem]) -> Result<(), spi::Error> {
+    let lines: Vec<freight::FreightLine> = items.iter()
+        .map(|item| freight::FreightLine {
+            seller_id: item.seller_id,
+            product_id: item.product_id,
+            quantity: item.quantity,
+            amount: item.unit_price * item.quantity as f32,
+        })
+        .collect();
+
+    let seller_ids: Vec<i32> = lines.iter().map(|line| line.seller_id).collect();
+    let table = Spi::get_one_with_args::<JsonB>(
+        freight::LOAD_SQL,
+        &[seller_ids.into(), freight::DEFAULT_SELLER_ID.into()],
+    )?;
+    let table: freight::FreightTable = match table.map(|table| serde_json::from_value(table.0)) {
+        Some(Ok(table)) => table,
+        Some(Err(e)) => error!("cart_ext: invalid freight rates: {e}"),
+        None => freight::FreightTable::default(),
+    };
+
+    for (item, freight_value) in items.iter_mut().zip(table.quote(&lines)) {
+        if let Some(freight_value) = freight_value {
+            item.freight_value = freight_value;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes the freight of cart items (as carried by ReserveStock) the way
+/// `cart_checkout` does, for callers that price a basket outside a checkout.
+/// Returns the items with FreightValue set.
+#[pg_extern]
+fn cart_freight_quote(items: JsonB) -> Result<JsonB, spi::Error> {
+    let mut items: Vec<CartItem> = match serde_json::from_value(items.0) {
+        Ok(items) => items,
+        Err(e) => error!("cart_freight_quote: expected an array of cart items: {e}"),
+    };
+    quote_freight(&mut items)?;
+    Ok(JsonB(json!(items)))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use crate::CartItem;
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+
+    fn item(seller_id: i32, product_id: i32, unit_price: f32, quantity: i32) -> CartItem {
+        CartItem { seller_id, product_id, unit_price, quantity, ..Default::default() }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "expected {expected}, got {actual}");
+    }
+
+    fn define(code: &str, kind: &str, amount: f32, seller_id: Option<i32>, min_basket: f32) -> Result<(), spi::Error> {
+        crate::cart_define_voucher(code, kind, amount, seller_id, min_basket, None, None)
+    }
+
+    #[pg_test]
+    fn vouchers_apply_in_applied_order() -> Result<(), spi::Error> {
+        crate::setup_cart()?;
+        define("PCT10", "percentage", 10.0, None, 0.0)?;
+        define("FIX20", "fixed", 20.0, Some(2), 0.0)?;
+        crate::cart_apply_voucher(1, "PCT10")?;
+        crate::cart_apply_voucher(1, "FIX20")?;
+        // PCT10 was applied first, against the code order the rows are locked in
+        Spi::run("UPDATE CART_VOUCHER_APPLICATIONS SET applied_at = now() + make_interval(secs => CASE code WHEN 'PCT10' THEN 1 ELSE 2 END)")?;
+
+        let mut items = vec![item(1, 1, 50.0, 2), item(2, 1, 100.0, 1)];
+        let pricing = crate::price_items(1, &mut items, true)?;
+
+        // 10% of 200 split by amount, then 20 off seller 2's remaining 90
+        let codes: Vec<&str> = pricing.applied.iter().map(|(code, _)| code.as_str()).collect();
+        assert_eq!(codes, vec!["PCT10", "FIX20"]);
+        assert_close(pricing.applied[0].1, 20.0);
+        assert_close(pricing.applied[1].1, 20.0);
+        assert_close(pricing.discounts[0], 10.0);
+        assert_close(pricing.discounts[1], 30.0);
+        assert_close(items[1].voucher, 30.0);
+        assert!(pricing.rejected.is_empty());
+        Ok(())
+    }
+
+    #[pg_test]
+    fn ineligible_vouchers_are_rejected() -> Result<(), spi::Error> {
+        crate::setup_cart()?;
+        define("EXPIRED", "fixed", 5.0, None, 0.0)?;
+        define("BIGBASKET", "fixed", 5.0, None, 500.0)?;
+        define("USEDUP", "fixed", 5.0, None, 0.0)?;
+        define("OTHERSELLER", "fixed", 5.0, Some(3), 0.0)?;
+        Spi::run("UPDATE CART_VOUCHERS SET expires_at = now() - interval '1 day' WHERE code = 'EXPIRED'")?;
+        Spi::run("UPDATE CART_VOUCHERS SET usage_limit = 1, usage_count = 1 WHERE code = 'USEDUP'")?;
+        for code in ["EXPIRED", "BIGBASKET", "USEDUP", "OTHERSELLER"] {
+            crate::cart_apply_voucher(1, code)?;
+        }
+
+        let mut items = vec![item(1, 1, 50.0, 2)];
+        let mut rejected = crate::price_items(1, &mut items, false)?.rejected;
+        rejected.sort();
+
+        assert_eq!(rejected, vec![
+            ("BIGBASKET".to_string(), "below minimum basket"),
+            ("EXPIRED".to_string(), "expired"),
+            ("OTHERSELLER".to_string(), "no eligible items"),
+            ("USEDUP".to_string(), "usage limit reached"),
+        ]);
+        assert_close(items[0].voucher, 0.0);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn redeeming_counts_usage_and_clears_applications() -> Result<(), spi::Error> {
+        crate::setup_cart()?;
+        define("FIX5", "fixed", 5.0, None, 0.0)?;
+        crate::cart_apply_voucher(1, "FIX5")?;
+
+        let mut items = vec![item(1, 1, 50.0, 1)];
+        let pricing = crate::price_items(1, &mut items, true)?;
+        crate::redeem_vouchers(1, "i-1", &pricing)?;
+
+        assert_eq!(Spi::get_one::<i32>("SELECT usage_count FROM CART_VOUCHERS WHERE code = 'FIX5'")?, Some(1));
+        assert_eq!(Spi::get_one::<i64>("SELECT count(*) FROM CART_VOUCHER_APPLICATIONS")?, Some(0));
+        let discount = Spi::get_one::<f32>("SELECT discount FROM CART_VOUCHER_REDEMPTIONS WHERE instance_id = 'i-1'")?;
+        assert_close(discount.expect("a redemption was recorded"), 5.0);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn freight_quote_splits_seller_fee_and_keeps_unrated_freight() -> Result<(), spi::Error> {
+        crate::setup_cart()?;
+        Spi::run("INSERT INTO FREIGHT_RATES (seller_id, base_fee) VALUES (1, 6)")?;
+        let items = serde_json::json!([
+            { "SellerId": 1, "ProductId": 1, "UnitPrice": 10.0, "Quantity": 1, "FreightValue": 0.0 },
+            { "SellerId": 1, "ProductId": 2, "UnitPrice": 10.0, "Quantity": 2, "FreightValue": 0.0 },
+            { "SellerId": 2, "ProductId": 1, "UnitPrice": 10.0, "Quantity": 1, "FreightValue": 3.5 },
+        ]);
+
+        let quoted: Vec<CartItem> = serde_json::from_value(crate::cart_freight_quote(pgrx::JsonB(items))?.0)
+            .expect("the quote returns cart items");
+        let freight: Vec<f32> = quoted.iter().map(|item| item.freight_value).collect();
+        assert_eq!(freight.len(), 3);
+        assert_close(freight[0], 2.0);
+        assert_close(freight[1], 4.0);
+        assert_close(freight[2], 3.5);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn expiry_empties_only_carts_idle_beyond_the_ttl() -> Result<(), spi::Error> {
+        crate::setup_cart()?;
+        Spi::run(r#"
+            CREATE SCHEMA cart;
+            CREATE TABLE cart.carts (customer_id INT PRIMARY KEY, status TEXT NOT NULL, updated_at TIMESTAMPTZ NOT NULL);
+            CREATE TABLE cart.cart_items (
+                customer_id INT NOT NULL,
+                seller_id INT NOT NULL,
+                product_id INT NOT NULL,
+                product_name TEXT NOT NULL,
+                unit_price REAL NOT NULL,
+                freight_value REAL NOT NULL,
+                quantity INT NOT NULL,
+                voucher REAL NOT NULL,
+                version TEXT NOT NULL,
+                PRIMARY KEY (customer_id, seller_id, product_id)
+            );
+        "#)?;
+        crate::setup_cart_expiry(3600.0, 60.0, 100)?;
+        Spi::run(r#"
+            INSERT INTO cart.carts VALUES
+                (1, 'OPEN', now() - interval '2 hours'),
+                (2, 'OPEN', now() - interval '2 hours'),
+                (3, 'CHECKOUT_SENT', now() - interval '2 hours');
+            INSERT INTO cart.cart_items
+            SELECT customer_id, 1, product_id, 'p', 10, 0, 1, 0, '0'
+            FROM (VALUES (1, 1), (1, 2), (2, 1), (3, 1)) AS i(customer_id, product_id);
+            -- customer 2 added an item just now, the others before the horizon
+            UPDATE CART_ITEM_ACTIVITY SET last_added_at = now() - interval '2 hours' WHERE customer_id <> 2;
+        "#)?;
+
+        assert_eq!(crate::cart_expire_abandoned()?, 1);
+        let remaining = Spi::get_one::<Vec<i32>>(
+            "SELECT array_agg(DISTINCT customer_id ORDER BY customer_id) FROM cart.cart_items",
+        )?;
+        assert_eq!(remaining, Some(vec![2, 3]));
+        let metrics_sql = "SELECT value FROM CART_METRICS WHERE metric = $1";
+        assert_eq!(Spi::get_one_with_args::<i64>(metrics_sql, &["carts_abandoned".into()])?, Some(1));
+        assert_eq!(Spi::get_one_with_args::<i64>(metrics_sql, &["cart_items_expired".into()])?, Some(2));
+
+        // the emptied cart has no items left, so a second scan finds nothing
+        assert_eq!(crate::cart_expire_abandoned()?, 0);
+        assert_eq!(Spi::get_one_with_args::<i64>(metrics_sql, &["carts_abandoned".into()])?, Some(1));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
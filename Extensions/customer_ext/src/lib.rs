@@ -1,10 +1,13 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder}, datum::TimestampWithTimeZone, default, log, name,
+    pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB, TableIterator,
 };
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -26,24 +29,34 @@ static CONN_INFO: &str =
 
 const BGW_ID_OFFSET: i32 = 11;  // Ensure unique BGW IDs (11-14)
 
+const MAX_BATCH_SIZE: usize = 256;  // Notifications applied per transaction
+
 ////////////////////////////////////////
 // 2. Start BGWs
 ////////////////////////////////////////
 
+/// Starts the listeners for order events, which require
+/// `setup_customer_stats()` and `setup_customer_inbox()`. Every event lands in
+/// the customer's inbox. By default each event is also relayed to CustomerMS
+/// on its out channel, which keeps its own payment and delivery counters, and
+/// only the statistics CustomerMS does not keep (abandoned carts, total spent)
+/// are applied here. With `forward => false` every statistic is applied here
+/// instead, so CustomerMS and the extension never count the same event twice.
 #[pg_extern]
-fn customer_listen_to_changes() -> Result<(), String> {
+fn customer_listen_to_changes(forward: default!(bool, true)) -> Result<(), String> {
     for (i, _) in CHANNELS.iter().enumerate() {
-        spawn_listener(i as i32 + BGW_ID_OFFSET)?;
+        spawn_listener(i as i32 + BGW_ID_OFFSET, forward)?;
     }
     Ok(())
 }
 
-fn spawn_listener(id: i32) -> Result<(), String> {
+fn spawn_listener(id: i32, forward: bool) -> Result<(), String> {
     BackgroundWorkerBuilder::new("customer_listener")
         .set_library("customer_ext")
         .set_function("listen_bgworker")
         .enable_spi_access()
         .set_argument(id.into_datum())
+        .set_extra(if forward { "forward" } else { "" })
         .load_dynamic();
     Ok(())
 }
@@ -87,7 +100,12 @@ pub extern "C" fn listen_bgworker(arg: pg_sys::Datum) {
 
 fn run_bgworker(id: i32) {
     let (in_channel, out_channel) = CHANNELS[(id - BGW_ID_OFFSET) as usize];
-    log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    let forward = BackgroundWorker::get_extra() == "forward";
+    if forward {
+        log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    } else {
+        log!("BGW {id}: Starting, listening on `{in_channel}`");
+    }
 
     loop {
         pgrx::check_for_interrupts!();
@@ -105,24 +123,40 @@ fn run_bgworker(id: i32) {
                 loop {
                     pgrx::check_for_interrupts!();
 
-                    match notifications.blocking_iter().next() {
+                    let next = notifications.blocking_iter().next();
+                    match next {
                         Ok(Some(notification)) => {
-                            let payload = notification.payload().to_string();
+                            // Batch whatever else is already pending
+                            let mut pending = notifications.iter();
+                            let payloads = take_batch(
+                                notification.payload().to_string(),
+                                std::iter::from_fn(|| pending.next().ok().flatten().map(|n| n.payload().to_string())),
+                            );
 
                             // Start a transaction
                             unsafe { pg_sys::StartTransactionCommand(); }
                             unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()); }
 
-                            // Notify via SPI
-                            let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
-                            let spi_result = Spi::run_with_args(&notify_sql, &[payload.clone().into()]);
+                            // Notify via SPI if forwarding, then apply the batch
+                            let events = parse_customer_events(in_channel, &payloads);
+                            let notified = if forward {
+                                let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
+                                payloads.iter().try_for_each(|payload| {
+                                    Spi::run_with_args(&notify_sql, &[payload.as_str().into()])
+                                })
+                            } else {
+                                Ok(())
+                            };
+                            let spi_result = notified
+                                .and_then(|_| apply_customer_stats(in_channel, &events, forward))
+                                .and_then(|_| record_customer_inbox(in_channel, &events));
 
                             // Cleanup transaction
                             unsafe { pg_sys::PopActiveSnapshot(); }
                             unsafe { pg_sys::CommitTransactionCommand(); }
 
                             if let Err(e) = spi_result {
-                                log!("BGW {id}: SPI error while NOTIFY: {e}");
+                                log!("BGW {id}: SPI error while handling `{in_channel}` events: {e}");
                             }
                         }
                         Ok(None) => break,
@@ -141,3 +175,409 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 5. Customer Statistics
+////////////////////////////////////////
+
+/// Adds the statistics CustomerMS does not keep to customer.customers.
+/// Requires CustomerMS' customer.customers.
+#[pg_extern]
+fn setup_customer_stats() -> Result<(), spi::Error> {
+    Spi::run(r#"
+        ALTER TABLE customer.customers ADD COLUMN IF NOT EXISTS abandoned_cart_count INT NOT NULL DEFAULT 0;
+        ALTER TABLE customer.customers ADD COLUMN IF NOT EXISTS total_spent FLOAT8 NOT NULL DEFAULT 0;
+    "#)?;
+    Ok(())
+}
+
+/// Takes `first` and up to MAX_BATCH_SIZE - 1 further payloads from `pending`,
+/// leaving the rest for the next batch.
+fn take_batch(first: String, pending: impl Iterator<Item = String>) -> Vec<String> {
+    let mut payloads = vec![first];
+    payloads.extend(pending.take(MAX_BATCH_SIZE - 1));
+    payloads
+}
+
+/// The events of a batch with the customer they concern; malformed payloads
+/// and events without a customer are skipped.
+fn parse_customer_events(channel: &str, payloads: &[String]) -> Vec<(i64, Value)> {
+    let mut events = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        let event: Value = match serde_json::from_str(payload) {
+            Ok(event) => event,
+            Err(e) => {
+                log!("customer_ext: malformed `{channel}` payload: {e}");
+                continue;
+            }
+        };
+        let customer_id = match channel {
+            "stock_failed" => event["customerCheckout"]["CustomerId"].as_i64(),
+            "delivery" => event["customerId"].as_i64(),
+            _ => event["customer"]["CustomerId"].as_i64(),
+        };
+        if let Some(customer_id) = customer_id {
+            events.push((customer_id, event));
+        }
+    }
+    events
+}
+
+#[derive(Default)]
+struct CustomerDelta {
+    success_payment_count: i32,
+    failed_payment_count: i32,
+    abandoned_cart_count: i32,
+    delivery_count: i32,
+    total_spent: f64,
+}
+
+/// Folds a batch of events of one channel into one delta per customer and
+/// applies them with a single statement, as CustomerService would one by one.
+/// A stock_failed event counts as an abandoned cart. With `forwarded`,
+/// CustomerMS counts payments and deliveries itself, so only the statistics
+/// it does not keep are applied.
+fn apply_customer_stats(channel: &str, events: &[(i64, Value)], forwarded: bool) -> Result<(), spi::Error> {
+    if forwarded && matches!(channel, "delivery" | "payment_failed") {
+        return Ok(());
+    }
+    let mut deltas: BTreeMap<i64, CustomerDelta> = BTreeMap::new();
+    for (customer_id, event) in events {
+        let delta = deltas.entry(*customer_id).or_default();
+        match channel {
+            "stock_failed" => delta.abandoned_cart_count += 1,
+            "delivery" => delta.delivery_count += 1,
+            "payment_failed" => delta.failed_payment_count += 1,
+            "payment_confirmed" => {
+                if !forwarded {
+                    delta.success_payment_count += 1;
+                }
+                delta.total_spent += event["totalAmount"].as_f64().unwrap_or_default();
+            }
+            _ => {}
+        }
+    }
+    if deltas.is_empty() {
+        return Ok(());
+    }
+
+    // lock the customers in id order, so concurrent batches cannot deadlock
+    let customer_ids: Vec<i32> = deltas.keys().map(|&customer_id| customer_id as i32).collect();
+    Spi::run_with_args(
+        "SELECT 1 FROM customer.customers WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+        &[customer_ids.into()],
+    )?;
+
+    let deltas: Vec<Value> = deltas.into_iter()
+        .map(|(customer_id, delta)| json!({
+            "customer_id": customer_id,
+            "success_payment_count": delta.success_payment_count,
+            "failed_payment_count": delta.failed_payment_count,
+            "abandoned_cart_count": delta.abandoned_cart_count,
+            "delivery_count": delta.delivery_count,
+            "total_spent": delta.total_spent,
+        }))
+        .collect();
+
+    let update_sql = r#"
+        UPDATE customer.customers c SET
+            success_payment_count = c.success_payment_count + d.success_payment_count,
+            failed_payment_count = c.failed_payment_count + d.failed_payment_count,
+            abandoned_cart_count = c.abandoned_cart_count + d.abandoned_cart_count,
+            delivery_count = c.delivery_count + d.delivery_count,
+            total_spent = c.total_spent + d.total_spent,
+            updated_at = now()
+        FROM jsonb_to_recordset($1) AS d(
+            customer_id INT, success_payment_count INT, failed_payment_count INT,
+            abandoned_cart_count INT, delivery_count INT, total_spent FLOAT8
+        )
+        WHERE c.id = d.customer_id;
+    "#;
+    Spi::run_with_args(update_sql, &[JsonB(Value::Array(deltas)).into()])
+}
+
+#[pg_extern]
+fn customer_stats(customer_id: i32) -> Result<JsonB, spi::Error> {
+    let stats_sql = r#"
+        SELECT jsonb_build_object(
+            'customer_id', id,
+            'success_payment_count', success_payment_count,
+            'failed_payment_count', failed_payment_count,
+            'abandoned_cart_count', abandoned_cart_count,
+            'delivery_count', delivery_count,
+            'total_spent', total_spent
+        )
+        FROM customer.customers
+        WHERE id = $1;
+    "#;
+    match Spi::get_one_with_args::<JsonB>(stats_sql, &[customer_id.into()]) {
+        Ok(stats) => Ok(stats.unwrap_or(JsonB(Value::Null))),
+        Err(spi::Error::InvalidPosition) => Ok(JsonB(Value::Null)),
+        Err(e) => Err(e),
+    }
+}
+
+////////////////////////////////////////
+// 6. Customer Inbox
+////////////////////////////////////////
+
+/// Creates the inbox the listeners fill with every delivery, payment and
+/// stock failure event of a customer.
+#[pg_extern]
+fn setup_customer_inbox() -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS customer.customer_inbox (
+            id BIGSERIAL PRIMARY KEY,
+            customer_id INT NOT NULL,
+            event_type TEXT NOT NULL,
+            order_id INT,
+            payload JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            read_at TIMESTAMPTZ
+        );
+        CREATE INDEX IF NOT EXISTS customer_inbox_timeline_idx ON customer.customer_inbox (customer_id, created_at);
+        CREATE INDEX IF NOT EXISTS customer_inbox_unread_idx ON customer.customer_inbox (customer_id) WHERE read_at IS NULL;
+    "#)?;
+    Ok(())
+}
+
+/// Adds every event of a batch to the inbox of the customer it concerns.
+fn record_customer_inbox(channel: &str, events: &[(i64, Value)]) -> Result<(), spi::Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let inbox: Vec<Value> = events.iter()
+        .map(|(customer_id, event)| json!({ "customer_id": customer_id, "order_id": event["orderId"], "payload": event }))
+        .collect();
+    Spi::run_with_args(
+        r#"
+        INSERT INTO customer.customer_inbox (customer_id, event_type, order_id, payload)
+        SELECT customer_id, $1, order_id, payload
+        FROM jsonb_to_recordset($2) AS i(customer_id INT, order_id INT, payload JSONB);
+        "#,
+        &[channel.into(), JsonB(Value::Array(inbox)).into()],
+    )
+}
+
+/// The customer's events since `since`, newest first. `event_type` is the
+/// channel the event arrived on.
+#[pg_extern]
+fn customer_inbox(
+    customer_id: i32,
+    since: default!(Option<TimestampWithTimeZone>, "NULL"),
+    limit: default!(i64, 50),
+) -> Result<
+    TableIterator<'static, (
+        name!(id, i64),
+        name!(event_type, String),
+        name!(order_id, Option<i32>),
+        name!(payload, JsonB),
+        name!(created_at, TimestampWithTimeZone),
+        name!(read_at, Option<TimestampWithTimeZone>),
+    )>,
+    spi::Error,
+> {
+    let inbox_sql = r#"
+        SELECT id, event_type, order_id, payload, created_at, read_at
+        FROM customer.customer_inbox
+        WHERE customer_id = $1 AND ($2::TIMESTAMPTZ IS NULL OR created_at > $2)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $3;
+    "#;
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        for row in client.select(inbox_sql, None, &[customer_id.into(), since.into(), limit.into()])? {
+            rows.push((
+                row.get_by_name::<i64, _>("id")?.unwrap_or_default(),
+                row.get_by_name::<String, _>("event_type")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("order_id")?,
+                row.get_by_name::<JsonB, _>("payload")?.unwrap_or(JsonB(Value::Null)),
+                row.get_by_name::<TimestampWithTimeZone, _>("created_at")?
+                    .expect("customer_inbox.created_at is NOT NULL"),
+                row.get_by_name::<TimestampWithTimeZone, _>("read_at")?,
+            ));
+        }
+        Ok::<_, spi::Error>(rows)
+    })?;
+    Ok(TableIterator::new(rows))
+}
+
+/// Marks the given entries of the customer's inbox read, or all of them when
+/// `ids` is NULL. Returns the number newly marked.
+#[pg_extern]
+fn customer_inbox_mark_read(
+    customer_id: i32, ids: default!(Option<Vec<i64>>, "NULL"),
+) -> Result<i64, spi::Error> {
+    let mark_sql = r#"
+        WITH marked AS (
+            UPDATE customer.customer_inbox SET read_at = clock_timestamp()
+            WHERE customer_id = $1 AND read_at IS NULL AND ($2::BIGINT[] IS NULL OR id = ANY($2))
+            RETURNING 1
+        )
+        SELECT count(*) FROM marked;
+    "#;
+    let marked = Spi::get_one_with_args::<i64>(mark_sql, &[customer_id.into(), ids.into()])?;
+    Ok(marked.unwrap_or(0))
+}
+
+#[pg_extern]
+fn customer_inbox_unread_count(customer_id: i32) -> Result<i64, spi::Error> {
+    let unread = Spi::get_one_with_args::<i64>(
+        "SELECT count(*) FROM customer.customer_inbox WHERE customer_id = $1 AND read_at IS NULL",
+        &[customer_id.into()],
+    )?;
+    Ok(unread.unwrap_or(0))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+    use serde_json::{json, Value};
+
+    fn setup_customer_tables() -> Result<(), spi::Error> {
+        Spi::run(r#"
+            CREATE SCHEMA customer;
+            CREATE TABLE customer.customers (
+                id INT PRIMARY KEY,
+                success_payment_count INT NOT NULL DEFAULT 0,
+                failed_payment_count INT NOT NULL DEFAULT 0,
+                delivery_count INT NOT NULL DEFAULT 0,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            INSERT INTO customer.customers (id) VALUES (1), (2);
+        "#)?;
+        crate::setup_customer_stats()?;
+        crate::setup_customer_inbox()
+    }
+
+    fn payment_confirmed(customer_id: i32, order_id: i32, total_amount: f64) -> String {
+        json!({ "customer": { "CustomerId": customer_id }, "orderId": order_id, "totalAmount": total_amount }).to_string()
+    }
+
+    fn stats(customer_id: i32) -> Result<Value, spi::Error> {
+        Ok(crate::customer_stats(customer_id)?.0)
+    }
+
+    #[pg_test]
+    fn batch_stops_at_max_batch_size() {
+        let pending: Vec<String> = (1..=300).map(|i| i.to_string()).collect();
+        let mut pending = pending.into_iter();
+        let batch = crate::take_batch("0".to_string(), pending.by_ref());
+        assert_eq!(batch.len(), crate::MAX_BATCH_SIZE);
+        assert_eq!(batch.first().map(String::as_str), Some("0"));
+        assert_eq!(pending.next().as_deref(), Some("256"));
+
+        let single = crate::take_batch("0".to_string(), std::iter::empty());
+        assert_eq!(single, vec!["0".to_string()]);
+    }
+
+    #[pg_test]
+    fn full_batch_folds_into_one_delta_per_customer() -> Result<(), spi::Error> {
+        setup_customer_tables()?;
+        let payloads: Vec<String> = (0..crate::MAX_BATCH_SIZE as i32)
+            .map(|i| payment_confirmed(1 + i % 2, i, 1.5))
+            .chain(["not json".to_string()])
+            .collect();
+        let events = crate::parse_customer_events("payment_confirmed", &payloads);
+        assert_eq!(events.len(), crate::MAX_BATCH_SIZE);
+        crate::apply_customer_stats("payment_confirmed", &events, false)?;
+
+        for customer_id in [1, 2] {
+            let stats = stats(customer_id)?;
+            assert_eq!(stats["success_payment_count"], 128);
+            assert_eq!(stats["total_spent"].as_f64(), Some(192.0));
+            assert_eq!(stats["failed_payment_count"], 0);
+        }
+        Ok(())
+    }
+
+    #[pg_test]
+    fn forwarded_events_only_apply_stats_customer_ms_does_not_keep() -> Result<(), spi::Error> {
+        setup_customer_tables()?;
+        let confirmed = crate::parse_customer_events("payment_confirmed", &[payment_confirmed(1, 1, 20.0)]);
+        crate::apply_customer_stats("payment_confirmed", &confirmed, true)?;
+        let delivery = crate::parse_customer_events(
+            "delivery", &[json!({ "customerId": 1, "orderId": 1 }).to_string()],
+        );
+        crate::apply_customer_stats("delivery", &delivery, true)?;
+        let stock_failed = crate::parse_customer_events(
+            "stock_failed", &[json!({ "customerCheckout": { "CustomerId": 1 } }).to_string()],
+        );
+        crate::apply_customer_stats("stock_failed", &stock_failed, true)?;
+
+        let forwarded = stats(1)?;
+        assert_eq!(forwarded["total_spent"].as_f64(), Some(20.0));
+        assert_eq!(forwarded["abandoned_cart_count"], 1);
+        assert_eq!(forwarded["success_payment_count"], 0);
+        assert_eq!(forwarded["delivery_count"], 0);
+
+        crate::apply_customer_stats("delivery", &delivery, false)?;
+        assert_eq!(stats(1)?["delivery_count"], 1);
+        Ok(())
+    }
+
+    fn record(channel: &str, payloads: &[Value]) -> Result<(), spi::Error> {
+        let payloads: Vec<String> = payloads.iter().map(Value::to_string).collect();
+        crate::record_customer_inbox(channel, &crate::parse_customer_events(channel, &payloads))
+    }
+
+    #[pg_test]
+    fn inbox_lists_newest_first_since_and_up_to_limit() -> Result<(), spi::Error> {
+        setup_customer_tables()?;
+        record("payment_failed", &[json!({ "customer": { "CustomerId": 1 }, "orderId": 1 })])?;
+        record("delivery", &[json!({ "customerId": 1, "orderId": 2 }), json!({ "customerId": 2, "orderId": 3 })])?;
+        record("stock_failed", &[json!({ "customerCheckout": { "CustomerId": 1 } })])?;
+
+        let inbox: Vec<_> = crate::customer_inbox(1, None, 50)?.collect();
+        let events: Vec<(&str, Option<i32>)> = inbox.iter().map(|e| (e.1.as_str(), e.2)).collect();
+        assert_eq!(events, vec![("stock_failed", None), ("delivery", Some(2)), ("payment_failed", Some(1))]);
+        assert!(inbox.iter().all(|e| e.5.is_none()));
+
+        let limited: Vec<_> = crate::customer_inbox(1, None, 2)?.map(|e| e.0).collect();
+        assert_eq!(limited, vec![inbox[0].0, inbox[1].0]);
+        let since: Vec<_> = crate::customer_inbox(1, Some(inbox[2].4), 50)?.map(|e| e.0).collect();
+        assert_eq!(since, vec![inbox[0].0, inbox[1].0]);
+        assert_eq!(crate::customer_inbox(2, None, 50)?.count(), 1);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn inbox_marks_entries_read_once() -> Result<(), spi::Error> {
+        setup_customer_tables()?;
+        record("delivery", &[
+            json!({ "customerId": 1, "orderId": 1 }),
+            json!({ "customerId": 1, "orderId": 2 }),
+            json!({ "customerId": 1, "orderId": 3 }),
+            json!({ "customerId": 2, "orderId": 4 }),
+        ])?;
+        assert_eq!(crate::customer_inbox_unread_count(1)?, 3);
+
+        let newest = crate::customer_inbox(1, None, 1)?.map(|e| e.0).collect::<Vec<_>>();
+        assert_eq!(crate::customer_inbox_mark_read(1, Some(newest.clone()))?, 1);
+        assert_eq!(crate::customer_inbox_mark_read(1, Some(newest))?, 0);
+        assert_eq!(crate::customer_inbox_unread_count(1)?, 2);
+        assert!(crate::customer_inbox(1, None, 1)?.all(|e| e.5.is_some()));
+
+        assert_eq!(crate::customer_inbox_mark_read(1, None)?, 2);
+        assert_eq!(crate::customer_inbox_unread_count(1)?, 0);
+        assert_eq!(crate::customer_inbox_unread_count(2)?, 1);
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
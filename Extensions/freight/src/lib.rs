@@ -0,0 +1,217 @@
+//! Freight calculator shared by cart_ext and shipment_ext, so cart totals and
+//! package freight come from the same rates. The rates live in tables every
+//! extension using the calculator creates with `SETUP_SQL` and reads with
+//! `LOAD_SQL`; this crate only does the arithmetic.
+
+use serde::Deserialize;
+
+/// Seller id whose rate and tiers apply to sellers without their own.
+pub const DEFAULT_SELLER_ID: i32 = 0;
+
+pub const SETUP_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS FREIGHT_RATES (
+        seller_id INT PRIMARY KEY,
+        base_fee REAL NOT NULL CHECK (base_fee >= 0),
+        free_shipping_threshold REAL
+    );
+    CREATE TABLE IF NOT EXISTS FREIGHT_TIERS (
+        seller_id INT NOT NULL,
+        measure TEXT NOT NULL CHECK (measure IN ('quantity', 'weight')),
+        min_value REAL NOT NULL,
+        fee REAL NOT NULL CHECK (fee >= 0),
+        PRIMARY KEY (seller_id, measure, min_value)
+    );
+    CREATE TABLE IF NOT EXISTS FREIGHT_PRODUCT_WEIGHTS (
+        seller_id INT NOT NULL,
+        product_id INT NOT NULL,
+        weight REAL NOT NULL CHECK (weight >= 0),
+        PRIMARY KEY (seller_id, product_id)
+    );
+"#;
+
+/// Loads the rates of the sellers in `$1` (INT[]) and the defaults of seller
+/// `$2` (`DEFAULT_SELLER_ID`) as one JSONB document that deserializes into
+/// `FreightTable`.
+pub const LOAD_SQL: &str = r#"
+    SELECT jsonb_build_object(
+        'rates', (SELECT coalesce(jsonb_agg(r), '[]') FROM FREIGHT_RATES r
+                  WHERE r.seller_id = ANY($1) OR r.seller_id = $2),
+        'tiers', (SELECT coalesce(jsonb_agg(t), '[]') FROM FREIGHT_TIERS t
+                  WHERE t.seller_id = ANY($1) OR t.seller_id = $2),
+        'weights', (SELECT coalesce(jsonb_agg(w), '[]') FROM FREIGHT_PRODUCT_WEIGHTS w
+                    WHERE w.seller_id = ANY($1))
+    );
+"#;
+
+#[derive(Deserialize)]
+pub struct FreightRate {
+    pub seller_id: i32,
+    pub base_fee: f32,
+    pub free_shipping_threshold: Option<f32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Measure {
+    Quantity,
+    Weight,
+}
+
+/// Fee added once a seller's shipment reaches `min_value` of `measure`. Only
+/// the highest tier reached counts.
+#[derive(Deserialize)]
+pub struct FreightTier {
+    pub seller_id: i32,
+    pub measure: Measure,
+    pub min_value: f32,
+    pub fee: f32,
+}
+
+#[derive(Deserialize)]
+pub struct ProductWeight {
+    pub seller_id: i32,
+    pub product_id: i32,
+    pub weight: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FreightTable {
+    pub rates: Vec<FreightRate>,
+    pub tiers: Vec<FreightTier>,
+    pub weights: Vec<ProductWeight>,
+}
+
+/// One item to be shipped; `amount` is what it counts towards free shipping.
+pub struct FreightLine {
+    pub seller_id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub amount: f32,
+}
+
+impl FreightTable {
+    /// The rate and tier owner of a seller: its own, else the default.
+    fn rate_of(&self, seller_id: i32) -> Option<&FreightRate> {
+        self.rates.iter().find(|r| r.seller_id == seller_id)
+            .or_else(|| self.rates.iter().find(|r| r.seller_id == DEFAULT_SELLER_ID))
+    }
+
+    fn tier_fee(&self, tier_owner: i32, measure: Measure, value: f32) -> f32 {
+        self.tiers.iter()
+            .filter(|t| t.seller_id == tier_owner && t.measure == measure && t.min_value <= value)
+            .max_by(|a, b| a.min_value.total_cmp(&b.min_value))
+            .map_or(0.0, |t| t.fee)
+    }
+
+    fn weight_of(&self, seller_id: i32, product_id: i32) -> f32 {
+        self.weights.iter()
+            .find(|w| w.seller_id == seller_id && w.product_id == product_id)
+            .map_or(0.0, |w| w.weight)
+    }
+
+    /// Freight of each line, in line order. Each seller's lines ship together:
+    /// nothing once they reach the free shipping threshold, else the base fee
+    /// plus the quantity and weight tier fees, split over the lines by
+    /// quantity. `None` for lines of sellers without a rate (and no default).
+    pub fn quote(&self, lines: &[FreightLine]) -> Vec<Option<f32>> {
+        let mut freight = vec![None; lines.len()];
+        let mut sellers: Vec<i32> = lines.iter().map(|l| l.seller_id).collect();
+        sellers.sort_unstable();
+        sellers.dedup();
+
+        for seller_id in sellers {
+            let Some(rate) = self.rate_of(seller_id) else { continue };
+            let seller_lines = || lines.iter().enumerate().filter(move |(_, l)| l.seller_id == seller_id);
+
+            let quantity: i32 = seller_lines().map(|(_, l)| l.quantity).sum();
+            let amount: f32 = seller_lines().map(|(_, l)| l.amount).sum();
+            let weight: f32 = seller_lines().map(|(_, l)| self.weight_of(seller_id, l.product_id) * l.quantity as f32).sum();
+
+            let fee = match rate.free_shipping_threshold {
+                Some(threshold) if amount >= threshold => 0.0,
+                _ => rate.base_fee
+                    + self.tier_fee(rate.seller_id, Measure::Quantity, quantity as f32)
+                    + self.tier_fee(rate.seller_id, Measure::Weight, weight),
+            };
+            for (i, line) in seller_lines() {
+                freight[i] = Some(if quantity > 0 { fee * line.quantity as f32 / quantity as f32 } else { 0.0 });
+            }
+        }
+        freight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(seller_id: i32, base_fee: f32, free_shipping_threshold: Option<f32>) -> FreightRate {
+        FreightRate { seller_id, base_fee, free_shipping_threshold }
+    }
+
+    fn tier(seller_id: i32, measure: Measure, min_value: f32, fee: f32) -> FreightTier {
+        FreightTier { seller_id, measure, min_value, fee }
+    }
+
+    fn line(seller_id: i32, product_id: i32, quantity: i32, amount: f32) -> FreightLine {
+        FreightLine { seller_id, product_id, quantity, amount }
+    }
+
+    #[test]
+    fn free_shipping_from_threshold() {
+        let table = FreightTable { rates: vec![rate(1, 10.0, Some(100.0))], ..Default::default() };
+        assert_eq!(table.quote(&[line(1, 1, 1, 99.0)]), vec![Some(10.0)]);
+        assert_eq!(table.quote(&[line(1, 1, 1, 60.0), line(1, 2, 1, 40.0)]), vec![Some(0.0), Some(0.0)]);
+    }
+
+    #[test]
+    fn only_highest_tier_reached_counts() {
+        let table = FreightTable {
+            rates: vec![rate(1, 5.0, None)],
+            tiers: vec![tier(1, Measure::Quantity, 2.0, 1.0), tier(1, Measure::Quantity, 5.0, 3.0)],
+            ..Default::default()
+        };
+        assert_eq!(table.quote(&[line(1, 1, 1, 10.0)]), vec![Some(5.0)]);
+        assert_eq!(table.quote(&[line(1, 1, 3, 10.0)]), vec![Some(6.0)]);
+        assert_eq!(table.quote(&[line(1, 1, 6, 10.0)]), vec![Some(8.0)]);
+    }
+
+    #[test]
+    fn sellers_without_rate_use_default() {
+        let table = FreightTable {
+            rates: vec![rate(DEFAULT_SELLER_ID, 4.0, None), rate(1, 10.0, None)],
+            tiers: vec![tier(DEFAULT_SELLER_ID, Measure::Quantity, 2.0, 1.0), tier(1, Measure::Quantity, 2.0, 7.0)],
+            ..Default::default()
+        };
+        assert_eq!(table.quote(&[line(1, 1, 2, 10.0), line(2, 1, 2, 10.0)]), vec![Some(17.0), Some(5.0)]);
+    }
+
+    #[test]
+    fn weight_tiers_count_weight_times_quantity() {
+        let table = FreightTable {
+            rates: vec![rate(1, 2.0, None)],
+            tiers: vec![tier(1, Measure::Weight, 10.0, 3.0)],
+            weights: vec![ProductWeight { seller_id: 1, product_id: 1, weight: 4.0 }],
+        };
+        assert_eq!(table.quote(&[line(1, 1, 2, 10.0)]), vec![Some(2.0)]);
+        assert_eq!(table.quote(&[line(1, 1, 3, 10.0)]), vec![Some(5.0)]);
+        assert_eq!(table.quote(&[line(1, 2, 5, 10.0)]), vec![Some(2.0)]);
+    }
+
+    #[test]
+    fn fee_is_split_by_quantity() {
+        let table = FreightTable { rates: vec![rate(1, 12.0, None)], ..Default::default() };
+        assert_eq!(
+            table.quote(&[line(1, 1, 1, 10.0), line(2, 1, 1, 10.0), line(1, 2, 3, 10.0)]),
+            vec![Some(3.0), None, Some(9.0)],
+        );
+    }
+
+    #[test]
+    fn sellers_without_rate_get_none() {
+        let table = FreightTable { rates: vec![rate(1, 12.0, None)], ..Default::default() };
+        assert_eq!(table.quote(&[line(2, 1, 1, 10.0)]), vec![None]);
+        assert_eq!(FreightTable::default().quote(&[line(1, 1, 1, 10.0)]), vec![None]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Transaction mark plumbing shared by the service extensions and test_ext:
+//! the mark table columns, the MARK_ARCHIVE table, purging marks into it and
+//! stamping marks with the open benchmark run, plus the loop their periodic
+//! background workers run and the events they publish.
+
+use pgrx::datum::TimestampWithTimeZone;
+use pgrx::log;
+use pgrx::pg_sys;
+use pgrx::spi::{self, Spi};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+pub const MARK_ARCHIVE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS MARK_ARCHIVE (
+        source_table TEXT NOT NULL,
+        mark JSONB NOT NULL,
+        archived_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+    );
+"#;
+
+/// Benchmark runs and the `mark_run_id()` default every mark table's
+/// `run_id` column uses, so marks are stamped with the open run whichever
+/// extension's setup ran first.
+pub const RUN_STAMP_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS BENCHMARK_RUNS (
+        run_id BIGSERIAL PRIMARY KEY,
+        label TEXT NOT NULL,
+        config JSONB NOT NULL DEFAULT '{}',
+        started_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+        ended_at TIMESTAMPTZ
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS benchmark_runs_single_open_idx
+        ON BENCHMARK_RUNS ((TRUE)) WHERE ended_at IS NULL;
+    CREATE OR REPLACE FUNCTION mark_run_id() RETURNS BIGINT
+        LANGUAGE sql STABLE
+        AS 'SELECT run_id FROM BENCHMARK_RUNS WHERE ended_at IS NULL';
+"#;
+
+/// Brings a mark table created before the timing, run and reason columns
+/// existed up to the current layout, and indexes `run_id` for run purges.
+/// Setups call it after their `CREATE TABLE IF NOT EXISTS`, which already
+/// declares the columns.
+pub fn ensure_mark_columns(table: &str) -> Result<(), spi::Error> {
+    Spi::run(&format!(r#"
+        ALTER TABLE {table} ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp();
+        ALTER TABLE {table} ADD COLUMN IF NOT EXISTS submitted_at TIMESTAMPTZ;
+        ALTER TABLE {table} ADD COLUMN IF NOT EXISTS run_id BIGINT;
+        ALTER TABLE {table} ALTER COLUMN run_id SET DEFAULT mark_run_id();
+        ALTER TABLE {table} ADD COLUMN IF NOT EXISTS reason TEXT;
+        CREATE INDEX IF NOT EXISTS {table}_run_idx ON {table} (run_id);
+    "#))
+}
+
+/// Deletes the marks `db` (any service when `None`) wrote to `table` before
+/// `before` or during `run_id`, optionally moving them to MARK_ARCHIVE.
+pub fn purge_marks_from(
+    table: &str, db: Option<&str>,
+    before: Option<TimestampWithTimeZone>, run_id: Option<i64>, archive: bool,
+) -> Result<i64, spi::Error> {
+    let purge_sql = format!(r#"
+        WITH purged AS (
+            DELETE FROM {table}
+            WHERE ($1::TEXT IS NULL OR db = $1) AND (created_at < $2 OR run_id = $3)
+            RETURNING *
+        ), archived AS (
+            INSERT INTO MARK_ARCHIVE (source_table, mark)
+            SELECT '{table}', to_jsonb(purged) FROM purged WHERE $4
+        )
+        SELECT count(*) FROM purged;
+    "#);
+    let purged = Spi::get_one_with_args::<i64>(&purge_sql, &[db.into(), before.into(), run_id.into(), archive.into()])?;
+    Ok(purged.unwrap_or(0))
+}
+
+/// An item of a cart, as carried by ReserveStock, StockConfirmed and the cart
+/// events. OrderMS' items have no Version, which then stays empty.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct CartItem {
+    pub seller_id: i32,
+    pub product_id: i32,
+    pub product_name: String,
+    pub unit_price: f32,
+    pub freight_value: f32,
+    pub quantity: i32,
+    pub voucher: f32,
+    pub version: String,
+}
+
+/// Publishes `payload` on `channel`; delivered when the transaction commits.
+pub fn notify(channel: &str, payload: &Value) -> Result<(), spi::Error> {
+    Spi::run_with_args("SELECT pg_notify($1, $2)", &[channel.into(), payload.to_string().into()])
+}
+
+/// Runs `scan` in its own transaction, then sleeps for the interval it returns,
+/// or `retry_secs` after an error. `what` describes what `scan` counts, for
+/// the log. Never returns; background workers call it after connecting.
+pub fn run_periodic(name: &str, what: &str, retry_secs: f64, scan: fn() -> Result<(i64, f64), spi::Error>) {
+    log!("{name}: Starting");
+
+    loop {
+        pgrx::check_for_interrupts!();
+
+        unsafe { pg_sys::StartTransactionCommand(); }
+        unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()); }
+
+        let result = scan();
+
+        unsafe { pg_sys::PopActiveSnapshot(); }
+        unsafe { pg_sys::CommitTransactionCommand(); }
+
+        let interval_secs = match result {
+            Ok((affected, interval_secs)) => {
+                if affected > 0 {
+                    log!("{name}: {affected} {what}");
+                }
+                interval_secs
+            }
+            Err(e) => {
+                log!("{name}: SPI error while scanning: {e}");
+                retry_secs
+            }
+        };
+        std::thread::sleep(Duration::from_secs_f64(interval_secs.max(0.1)));
+    }
+}
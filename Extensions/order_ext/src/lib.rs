@@ -1,10 +1,17 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
-    pg_sys::{self, panic::register_pg_guard_panic_hook},
+    bgworkers::BackgroundWorkerBuilder, datum::TimestampWithTimeZone, default, error, log, pg_extern, pg_guard,
+    pg_sys::{self, panic::{register_pg_guard_panic_hook, CaughtError}, PgTryBuilder},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
+use pgrx::prelude::{
+    ereport, name, pg_trigger, PgHeapTuple, PgLogLevel, PgSqlErrorCode, PgTrigger, TableIterator, WhoAllocated,
+};
+use marks::{notify, CartItem};
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -16,6 +23,8 @@ pgrx::pg_module_magic!();
 
 #[pg_extern]
 fn setup_order() -> Result<(), spi::Error> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     let create_table_sql = r#"
         CREATE TABLE IF NOT EXISTS CHECKOUT (
             stream_id TEXT NOT NULL,
@@ -23,10 +32,17 @@ fn setup_order() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             mark_status TEXT NOT NULL,
             customer_id TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
     Spi::run(create_table_sql)?;
+    marks::ensure_mark_columns("CHECKOUT")?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
     Ok(())
 }
 
@@ -37,19 +53,34 @@ fn setup_order() -> Result<(), spi::Error> {
 #[pg_extern]
 fn order_add_checkout_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    customer_id: &str, mark_status: &str, db: &str
+    customer_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        customer_id.into(), mark_status.into(), db.into(),
+        customer_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
 
+#[pg_extern]
+fn order_purge_marks(
+    before: TimestampWithTimeZone, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    marks::purge_marks_from("CHECKOUT", Some("order"), Some(before), None, archive)
+}
+
+#[pg_extern]
+fn order_purge_run_marks(
+    run_id: i64, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    marks::purge_marks_from("CHECKOUT", Some("order"), None, Some(run_id), archive)
+}
+
 ////////////////////////////////////////
 // 3. Background Workers (BGWs)
 ////////////////////////////////////////
@@ -179,3 +210,735 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 7. Order Creation
+////////////////////////////////////////
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StockConfirmed {
+    timestamp: String,
+    customer_checkout: Value,
+    items: Vec<CartItem>,
+    instance_id: String,
+}
+
+/// Creates the order, its items and its first history entry from a
+/// StockConfirmed event and publishes InvoiceIssued, as OrderService does.
+/// Everything happens in the caller's transaction and NOTIFY is delivered on
+/// commit, so the invoice is published if and only if the order exists.
+/// Returns the published InvoiceIssued.
+#[pg_extern]
+fn order_create_from_stock_confirmed(payload: JsonB) -> Result<JsonB, spi::Error> {
+    let stock_confirmed: StockConfirmed = match serde_json::from_value(payload.0) {
+        Ok(stock_confirmed) => stock_confirmed,
+        Err(e) => error!("order_ext: malformed StockConfirmed payload: {e}"),
+    };
+    let customer_id = match stock_confirmed.customer_checkout["CustomerId"].as_i64() {
+        Some(customer_id) => customer_id as i32,
+        None => error!("order_ext: StockConfirmed payload has no customer id"),
+    };
+    let items = &stock_confirmed.items;
+
+    // totals before and after vouchers; a voucher never takes an item below zero
+    let total_freight: f32 = items.iter().map(|item| item.freight_value).sum();
+    let total_items: f32 = items.iter().map(|item| item.unit_price * item.quantity as f32).sum();
+    let item_amounts: Vec<f32> = items.iter()
+        .map(|item| (item.unit_price * item.quantity as f32 - item.voucher).max(0.0))
+        .collect();
+    let total_amount: f32 = item_amounts.iter().sum();
+    let total_incentive = total_items - total_amount;
+    let total_invoice = total_amount + total_freight;
+
+    // the row lock on customer_orders serializes concurrent orders of a customer
+    let next_order_sql = r#"
+        INSERT INTO "order".customer_orders (customer_id, next_order_id) VALUES ($1, 1)
+        ON CONFLICT (customer_id) DO UPDATE SET next_order_id = customer_orders.next_order_id + 1
+        RETURNING next_order_id;
+    "#;
+    let order_id = Spi::get_one_with_args::<i32>(next_order_sql, &[customer_id.into()])?.unwrap_or(1);
+
+    let (issue_date, shipping_limit_date, invoice_number) = Spi::get_three_with_args::<JsonB, JsonB, String>(
+        "SELECT to_jsonb(now()), to_jsonb(now() + interval '3 days'), $1 || '-' || to_char(now() AT TIME ZONE 'UTC', 'YYYYMMDD') || '-' || $2",
+        &[customer_id.to_string().into(), order_id.to_string().into()],
+    )?;
+    let issue_date = issue_date.map(|date| date.0).unwrap_or(Value::Null);
+    let shipping_limit_date = shipping_limit_date.map(|date| date.0).unwrap_or(Value::Null);
+    let invoice_number = invoice_number.unwrap_or_default();
+
+    let insert_order_sql = r#"
+        INSERT INTO "order".orders (
+            customer_id, order_id, invoice_number, status, purchase_date, count_items,
+            created_at, updated_at, total_amount, total_freight, total_incentive, total_invoice, total_items
+        )
+        VALUES ($1, $2, $3, 'INVOICED', $4::TIMESTAMPTZ, $5, now(), now(), $6, $7, $8, $9, $10);
+    "#;
+    Spi::run_with_args(insert_order_sql, &[
+        customer_id.into(), order_id.into(), invoice_number.clone().into(),
+        stock_confirmed.timestamp.clone().into(), (items.len() as i32).into(),
+        total_amount.into(), total_freight.into(), total_incentive.into(),
+        total_invoice.into(), total_items.into(),
+    ])?;
+
+    let insert_item_sql = r#"
+        INSERT INTO "order".order_items (
+            customer_id, order_id, order_item_id, product_id, product_name, seller_id,
+            unit_price, shipping_limit_date, freight_value, quantity, total_items, total_amount
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, now() + interval '3 days', $8, $9, $10, $11);
+    "#;
+    let mut order_items = Vec::with_capacity(items.len());
+    for (i, (item, item_amount)) in items.iter().zip(&item_amounts).enumerate() {
+        let order_item_id = i as i32 + 1;
+        let item_total = item.unit_price * item.quantity as f32;
+        Spi::run_with_args(insert_item_sql, &[
+            customer_id.into(), order_id.into(), order_item_id.into(),
+            item.product_id.into(), item.product_name.clone().into(), item.seller_id.into(),
+            item.unit_price.into(), item.freight_value.into(), item.quantity.into(),
+            item_total.into(), (*item_amount).into(),
+        ])?;
+        order_items.push(json!({
+            "order_id": order_id,
+            "order_item_id": order_item_id,
+            "product_id": item.product_id,
+            "product_name": item.product_name,
+            "seller_id": item.seller_id,
+            "unit_price": item.unit_price,
+            "shipping_limit_date": shipping_limit_date,
+            "freight_value": item.freight_value,
+            "quantity": item.quantity,
+            "total_items": item_total,
+            "total_amount": item_amount,
+            "total_incentive": item.voucher,
+        }));
+    }
+
+    Spi::run_with_args(
+        r#"INSERT INTO "order".order_history (customer_id, order_id, created_at, status) VALUES ($1, $2, now(), 'INVOICED');"#,
+        &[customer_id.into(), order_id.into()],
+    )?;
+
+    let invoice = json!({
+        "customer": stock_confirmed.customer_checkout,
+        "orderId": order_id,
+        "invoiceNumber": invoice_number,
+        "issueDate": issue_date,
+        "totalInvoice": total_invoice,
+        "items": order_items,
+        "instanceId": stock_confirmed.instance_id,
+    });
+    notify("invoice_issued", &invoice)?;
+
+    Ok(JsonB(invoice))
+}
+
+////////////////////////////////////////
+// 8. Order Status State Machine
+////////////////////////////////////////
+
+// Legal transitions per OrderStatus; DELIVERED, CANCELED and UNAVAILABLE are final.
+// Shipment notifications may overtake payment_confirmed, so INVOICED can move
+// straight to READY_FOR_SHIPMENT.
+static ORDER_TRANSITIONS: [(&str, &[&str]); 8] = [
+    ("CREATED", &["PROCESSING", "APPROVED", "INVOICED", "CANCELED", "UNAVAILABLE"]),
+    ("PROCESSING", &["APPROVED", "INVOICED", "CANCELED", "UNAVAILABLE"]),
+    ("APPROVED", &["INVOICED", "CANCELED", "UNAVAILABLE"]),
+    ("INVOICED", &["PAYMENT_PROCESSED", "PAYMENT_FAILED", "READY_FOR_SHIPMENT", "CANCELED"]),
+    ("PAYMENT_PROCESSED", &["READY_FOR_SHIPMENT", "CANCELED"]),
+    ("PAYMENT_FAILED", &["CANCELED"]),
+    ("READY_FOR_SHIPMENT", &["IN_TRANSIT", "DELIVERED"]),
+    ("IN_TRANSIT", &["DELIVERED"]),
+];
+
+// An order is created in one of these states and moves on from there
+static INITIAL_STATUSES: [&str; 2] = ["CREATED", "INVOICED"];
+
+// Payment and shipment events are consumed independently, so shipment may
+// overtake payment; the late PAYMENT_PROCESSED then leaves the order as it is
+static OVERTAKEN_BY_SHIPMENT: [&str; 3] = ["READY_FOR_SHIPMENT", "IN_TRANSIT", "DELIVERED"];
+
+fn is_legal_transition(from: &str, to: &str) -> bool {
+    ORDER_TRANSITIONS.iter().any(|(status, next)| *status == from && next.contains(&to))
+}
+
+/// Installs the trigger that enforces ORDER_TRANSITIONS on "order".orders.
+/// Requires the tables to exist.
+#[pg_extern]
+fn setup_order_state_machine() -> Result<(), spi::Error> {
+    let create_trigger_sql = r#"
+        DROP TRIGGER IF EXISTS order_status_transition ON "order".orders;
+        CREATE TRIGGER order_status_transition
+        BEFORE INSERT OR UPDATE OF status ON "order".orders
+        FOR EACH ROW
+        EXECUTE FUNCTION order_enforce_status_transition();
+    "#;
+    Spi::run(create_trigger_sql)?;
+    Ok(())
+}
+
+#[pg_extern]
+fn order_status_transitions() -> TableIterator<'static, (name!(from_status, String), name!(to_status, String))> {
+    let transitions: Vec<_> = ORDER_TRANSITIONS.iter()
+        .flat_map(|(from, next)| next.iter().map(move |to| (from.to_string(), to.to_string())))
+        .collect();
+    TableIterator::new(transitions)
+}
+
+#[derive(Debug)]
+enum TransitionError {
+    MissingTuple,
+    Datum(pgrx::datum::TryFromDatumError),
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::MissingTuple => write!(f, "trigger fired without a NEW row"),
+            TransitionError::Datum(e) => write!(f, "cannot read order column: {e}"),
+        }
+    }
+}
+
+impl From<pgrx::datum::TryFromDatumError> for TransitionError {
+    fn from(e: pgrx::datum::TryFromDatumError) -> Self {
+        TransitionError::Datum(e)
+    }
+}
+
+/// Rejects an order created in a non-initial status or moved along a
+/// transition outside ORDER_TRANSITIONS with SQLSTATE 09000
+/// (triggered_action_exception), which no constraint of the order tables
+/// raises. A PAYMENT_PROCESSED arriving after shipment started keeps the old
+/// row. History stays with whoever changes the status, as OrderService appends
+/// its own order_history entry for every transition.
+#[pg_trigger]
+fn order_enforce_status_transition<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, TransitionError> {
+    let new = trigger.new().ok_or(TransitionError::MissingTuple)?;
+    let customer_id = new.get_by_name::<i32>("customer_id")?.unwrap_or_default();
+    let order_id = new.get_by_name::<i32>("order_id")?.unwrap_or_default();
+    let to = new.get_by_name::<String>("status")?.unwrap_or_default();
+
+    let Some(old) = trigger.old() else {
+        if !INITIAL_STATUSES.contains(&to.as_str()) {
+            ereport!(
+                PgLogLevel::ERROR,
+                PgSqlErrorCode::ERRCODE_TRIGGERED_ACTION_EXCEPTION,
+                format!("order {customer_id}-{order_id} cannot be created as {to}")
+            );
+        }
+        return Ok(Some(new));
+    };
+
+    let from = old.get_by_name::<String>("status")?.unwrap_or_default();
+    if from == to {
+        return Ok(Some(new));
+    }
+    if to == "PAYMENT_PROCESSED" && OVERTAKEN_BY_SHIPMENT.contains(&from.as_str()) {
+        return Ok(Some(old));
+    }
+    if !is_legal_transition(&from, &to) {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_TRIGGERED_ACTION_EXCEPTION,
+            format!("illegal status transition of order {customer_id}-{order_id} from {from} to {to}")
+        );
+    }
+
+    Ok(Some(new))
+}
+
+////////////////////////////////////////
+// 9. Compensation
+////////////////////////////////////////
+
+static COMPENSATED_EVENTS: [&str; 2] = ["payment_failed", "stock_failed"];
+
+// Steps registered by `setup_order_compensation()`. Each statement gets the
+// instanceId as $1 and the failure event as $2. release_stock only releases
+// reservations made by stock_ext's native `stock_reserve()`; with StockMS
+// reserving, StockMS releases the stock on PaymentFailed itself and the step
+// settles nothing. notify_customer runs last and tells the customer, through
+// customer_ext's inbox, how the failure was settled: for payment_failed, the
+// outcome of the steps before it. A stock_failed checkout goes on with the
+// items that were reserved, so nothing is released or cancelled; the customer
+// is only told which products could not be reserved.
+static DEFAULT_COMPENSATION_STEPS: [(&str, i32, &str, &str); 4] = [
+    ("payment_failed", 10, "release_stock", "SELECT stock_cancel($1)"),
+    ("payment_failed", 20, "cancel_order", r#"
+        WITH failed AS (
+            UPDATE "order".orders SET status = 'PAYMENT_FAILED', updated_at = now()
+            WHERE customer_id = ($2->'customer'->>'CustomerId')::INT
+              AND order_id = ($2->>'orderId')::INT
+              AND status = 'INVOICED'
+            RETURNING customer_id, order_id
+        )
+        INSERT INTO "order".order_history (customer_id, order_id, created_at, status)
+        SELECT customer_id, order_id, now(), 'PAYMENT_FAILED' FROM failed
+    "#),
+    ("payment_failed", 30, "notify_customer", r#"
+        INSERT INTO customer.customer_inbox (customer_id, event_type, order_id, payload)
+        VALUES (($2->'customer'->>'CustomerId')::INT, 'compensated', ($2->>'orderId')::INT,
+                jsonb_build_object(
+                    'instanceId', $1, 'reason', 'payment_failed',
+                    'steps', (SELECT jsonb_object_agg(step_name, status) FROM COMPENSATION_LOG
+                              WHERE instance_id = $1 AND trigger_event = 'payment_failed')))
+    "#),
+    ("stock_failed", 10, "notify_customer", r#"
+        INSERT INTO customer.customer_inbox (customer_id, event_type, order_id, payload)
+        VALUES (($2->'customerCheckout'->>'CustomerId')::INT, 'compensated', NULL,
+                jsonb_build_object('instanceId', $1, 'reason', 'stock_failed', 'products', $2->'products'))
+    "#),
+];
+
+/// Creates the compensation step registry and log, and registers the default
+/// steps (release stock, cancel order, notify customer) unless steps of that
+/// name exist. The defaults rely on stock_ext's `stock_cancel()` and
+/// customer_ext's inbox; a step whose dependency is missing fails on its own.
+#[pg_extern]
+fn setup_order_compensation() -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS COMPENSATION_STEPS (
+            trigger_event TEXT NOT NULL,
+            step_name TEXT NOT NULL,
+            step_order INT NOT NULL,
+            statement TEXT NOT NULL,
+            enabled BOOLEAN NOT NULL DEFAULT TRUE,
+            PRIMARY KEY (trigger_event, step_name)
+        );
+        CREATE TABLE IF NOT EXISTS COMPENSATION_LOG (
+            instance_id TEXT NOT NULL,
+            trigger_event TEXT NOT NULL,
+            step_name TEXT NOT NULL,
+            status TEXT NOT NULL CHECK (status IN ('succeeded', 'failed')),
+            error TEXT,
+            attempts INT NOT NULL DEFAULT 1,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            PRIMARY KEY (instance_id, trigger_event, step_name)
+        );
+    "#)?;
+
+    for (trigger_event, step_order, step_name, statement) in DEFAULT_COMPENSATION_STEPS {
+        Spi::run_with_args(
+            r#"
+            INSERT INTO COMPENSATION_STEPS (trigger_event, step_name, step_order, statement)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (trigger_event, step_name) DO NOTHING;
+            "#,
+            &[trigger_event.into(), step_name.into(), step_order.into(), statement.into()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Registers (or replaces) a compensation step run on `trigger_event`, in
+/// `step_order`. The statement gets the instanceId as $1 and the event as $2.
+#[pg_extern]
+fn order_register_compensation_step(
+    trigger_event: &str,
+    step_name: &str,
+    step_order: i32,
+    statement: &str,
+    enabled: default!(bool, true),
+) -> Result<(), spi::Error> {
+    if !COMPENSATED_EVENTS.contains(&trigger_event) {
+        error!("order_register_compensation_step: `{trigger_event}` is not compensated");
+    }
+    Spi::run_with_args(
+        r#"
+        INSERT INTO COMPENSATION_STEPS (trigger_event, step_name, step_order, statement, enabled)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (trigger_event, step_name) DO UPDATE SET
+            step_order = EXCLUDED.step_order,
+            statement = EXCLUDED.statement,
+            enabled = EXCLUDED.enabled;
+        "#,
+        &[trigger_event.into(), step_name.into(), step_order.into(), statement.into(), enabled.into()],
+    )
+}
+
+/// Runs the enabled steps registered for `trigger_event` against one failure
+/// event, in step order, and records each outcome under the event's
+/// instanceId. Each step runs in its own subtransaction, so a failing step is
+/// rolled back alone and the others still run. Steps that already succeeded
+/// for the instance are skipped, so a redelivered event only retries the
+/// failed ones.
+#[pg_extern]
+fn order_compensate(trigger_event: &str, event: JsonB) -> Result<JsonB, spi::Error> {
+    let event = event.0;
+    let Some(instance_id) = event["instanceId"].as_str().map(str::to_string) else {
+        error!("order_compensate: `{trigger_event}` event has no instanceId");
+    };
+
+    // serializes redeliveries of the same event
+    Spi::run_with_args(
+        "SELECT pg_advisory_xact_lock(hashtext($1))",
+        &[instance_id.as_str().into()],
+    )?;
+
+    let steps_sql = r#"
+        SELECT s.step_name, s.statement, coalesce(l.status = 'succeeded', false) AS succeeded
+        FROM COMPENSATION_STEPS s
+        LEFT JOIN COMPENSATION_LOG l
+               ON l.instance_id = $1 AND l.trigger_event = s.trigger_event AND l.step_name = s.step_name
+        WHERE s.trigger_event = $2 AND s.enabled
+        ORDER BY s.step_order, s.step_name;
+    "#;
+    let steps = Spi::connect(|client| {
+        let mut steps = Vec::new();
+        for row in client.select(steps_sql, None, &[instance_id.as_str().into(), trigger_event.into()])? {
+            steps.push((
+                row.get_by_name::<String, _>("step_name")?.unwrap_or_default(),
+                row.get_by_name::<String, _>("statement")?.unwrap_or_default(),
+                row.get_by_name::<bool, _>("succeeded")?.unwrap_or_default(),
+            ));
+        }
+        Ok::<_, spi::Error>(steps)
+    })?;
+
+    let mut outcomes = Vec::with_capacity(steps.len());
+    for (step_name, statement, succeeded) in steps {
+        if succeeded {
+            outcomes.push(json!({ "step": step_name, "status": "skipped" }));
+            continue;
+        }
+
+        let result = run_compensation_step(&statement, &instance_id, &event);
+        let (status, error) = match &result {
+            Ok(()) => ("succeeded", None),
+            Err(e) => ("failed", Some(e.as_str())),
+        };
+        Spi::run_with_args(
+            r#"
+            INSERT INTO COMPENSATION_LOG (instance_id, trigger_event, step_name, status, error)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (instance_id, trigger_event, step_name) DO UPDATE SET
+                status = EXCLUDED.status,
+                error = EXCLUDED.error,
+                attempts = COMPENSATION_LOG.attempts + 1,
+                updated_at = clock_timestamp();
+            "#,
+            &[
+                instance_id.as_str().into(),
+                trigger_event.into(),
+                step_name.as_str().into(),
+                status.into(),
+                error.into(),
+            ],
+        )?;
+        outcomes.push(json!({ "step": step_name, "status": status, "error": error }));
+    }
+
+    Ok(JsonB(json!({
+        "instanceId": instance_id,
+        "event": trigger_event,
+        "steps": outcomes,
+    })))
+}
+
+/// Runs one step statement in an internal subtransaction, the way PL/pgSQL
+/// runs a block with an EXCEPTION clause, and returns its error message if it
+/// failed.
+fn run_compensation_step(statement: &str, instance_id: &str, event: &Value) -> Result<(), String> {
+    let (memory_context, resource_owner) = unsafe { (pg_sys::CurrentMemoryContext, pg_sys::CurrentResourceOwner) };
+    let restore = move || unsafe {
+        pg_sys::MemoryContextSwitchTo(memory_context);
+        pg_sys::CurrentResourceOwner = resource_owner;
+    };
+
+    unsafe {
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(memory_context);
+    }
+
+    PgTryBuilder::new(|| {
+        let result = Spi::run_with_args(statement, &[instance_id.into(), JsonB(event.clone()).into()]);
+        unsafe {
+            match result {
+                Ok(()) => pg_sys::ReleaseCurrentSubTransaction(),
+                Err(_) => pg_sys::RollbackAndReleaseCurrentSubTransaction(),
+            }
+        }
+        restore();
+        result.map_err(|e| e.to_string())
+    })
+    .catch_others(|e| {
+        restore();
+        unsafe { pg_sys::RollbackAndReleaseCurrentSubTransaction(); }
+        restore();
+        let message = match &e {
+            CaughtError::PostgresError(report)
+            | CaughtError::ErrorReport(report)
+            | CaughtError::RustPanic { ereport: report, .. } => report.message().to_string(),
+        };
+        Err(message)
+    })
+    .execute()
+}
+
+/// Starts the worker that runs `order_compensate()` for every payment_failed
+/// and stock_failed event. Requires `setup_order_compensation()`.
+#[pg_extern]
+fn order_start_compensation_worker() -> Result<(), String> {
+    BackgroundWorkerBuilder::new("order_compensation_worker")
+        .set_library("order_ext")
+        .set_function("compensation_bgworker")
+        .enable_spi_access()
+        .load_dynamic();
+    Ok(())
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn compensation_bgworker(_arg: pg_sys::Datum) {
+    register_pg_guard_panic_hook();
+
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnection(
+            b"postgres\0".as_ptr() as *const i8,
+            b"ucloud\0".as_ptr() as *const i8,
+            0,
+        );
+    }
+
+    log!("Compensation BGW: Starting, listening on {COMPENSATED_EVENTS:?}");
+
+    loop {
+        pgrx::check_for_interrupts!();
+
+        match Client::connect(CONN_INFO, NoTls) {
+            Ok(mut client) => {
+                let listen_stmt: String = COMPENSATED_EVENTS.iter().map(|channel| format!("LISTEN {channel};")).collect();
+                if let Err(e) = client.batch_execute(&listen_stmt) {
+                    log!("Compensation BGW: error LISTENing: {e} -> sleep 5s");
+                    std::thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+
+                let mut notifications = client.notifications();
+                loop {
+                    pgrx::check_for_interrupts!();
+
+                    match notifications.blocking_iter().next() {
+                        Ok(Some(notification)) => {
+                            let channel = notification.channel().to_string();
+                            let event: Value = match serde_json::from_str(notification.payload()) {
+                                Ok(event) => event,
+                                Err(e) => {
+                                    log!("Compensation BGW: malformed `{channel}` payload: {e}");
+                                    continue;
+                                }
+                            };
+                            if !event["instanceId"].is_string() {
+                                log!("Compensation BGW: `{channel}` event has no instanceId => skipped");
+                                continue;
+                            }
+
+                            unsafe { pg_sys::StartTransactionCommand(); }
+                            unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()); }
+
+                            let spi_result = order_compensate(&channel, JsonB(event));
+
+                            unsafe { pg_sys::PopActiveSnapshot(); }
+                            unsafe { pg_sys::CommitTransactionCommand(); }
+
+                            if let Err(e) = spi_result {
+                                log!("Compensation BGW: SPI error while compensating `{channel}` event: {e}");
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            log!("Compensation BGW: error receiving: {e} => break");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                log!("Compensation BGW: cannot connect => {e} => sleep 5s");
+                std::thread::sleep(Duration::from_secs(5));
+                continue;
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+    use pgrx::JsonB;
+    use serde_json::json;
+
+    // The subset of OrderMS' tables the state machine and compensation touch
+    fn setup_order_tables() -> Result<(), spi::Error> {
+        Spi::run(r#"
+            CREATE SCHEMA IF NOT EXISTS "order";
+            CREATE TABLE "order".orders (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                status TEXT NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (customer_id, order_id)
+            );
+            CREATE TABLE "order".order_history (
+                id SERIAL PRIMARY KEY,
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                status TEXT NOT NULL
+            );
+        "#)?;
+        crate::setup_order_state_machine()
+    }
+
+    fn order_status() -> Result<Option<String>, spi::Error> {
+        Spi::get_one(r#"SELECT status FROM "order".orders WHERE customer_id = 1 AND order_id = 1"#)
+    }
+
+    fn history() -> Result<Option<Vec<String>>, spi::Error> {
+        Spi::get_one(r#"SELECT array_agg(status ORDER BY id) FROM "order".order_history"#)
+    }
+
+    #[pg_test]
+    fn legal_transitions_leave_history_to_their_writer() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED')"#)?;
+        Spi::run(r#"UPDATE "order".orders SET status = 'PAYMENT_PROCESSED'"#)?;
+        Spi::run(r#"UPDATE "order".orders SET status = 'READY_FOR_SHIPMENT'"#)?;
+
+        assert_eq!(order_status()?.as_deref(), Some("READY_FOR_SHIPMENT"));
+        assert_eq!(history()?, None);
+        Ok(())
+    }
+
+    #[pg_test(error = "illegal status transition of order 1-1 from INVOICED to DELIVERED")]
+    fn illegal_transition_is_rejected() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED')"#)?;
+        Spi::run(r#"UPDATE "order".orders SET status = 'DELIVERED'"#)
+    }
+
+    #[pg_test(error = "order 1-1 cannot be created as IN_TRANSIT")]
+    fn creation_in_non_initial_status_is_rejected() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'IN_TRANSIT')"#)
+    }
+
+    #[pg_test]
+    fn rejected_transition_has_its_own_sqlstate() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED')"#)?;
+        // Any other SQLSTATE escapes the handler and fails the test
+        Spi::run(r#"
+            DO $$
+            BEGIN
+                UPDATE "order".orders SET status = 'DELIVERED';
+            EXCEPTION WHEN triggered_action_exception THEN
+                NULL;
+            END
+            $$;
+        "#)?;
+        assert_eq!(order_status()?.as_deref(), Some("INVOICED"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn late_payment_after_shipment_keeps_status() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED')"#)?;
+        Spi::run(r#"UPDATE "order".orders SET status = 'READY_FOR_SHIPMENT'"#)?;
+        Spi::run(r#"UPDATE "order".orders SET status = 'PAYMENT_PROCESSED'"#)?;
+
+        assert_eq!(order_status()?.as_deref(), Some("READY_FOR_SHIPMENT"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn compensation_isolates_failed_steps_and_retries_them() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        crate::setup_order_compensation()?;
+        Spi::run(r#"INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED')"#)?;
+
+        // stock_ext is not installed, so release_stock fails on its own
+        let event = json!({ "instanceId": "i-1", "customer": { "CustomerId": 1 }, "orderId": 1 });
+        let outcome = crate::order_compensate("payment_failed", JsonB(event.clone()))?.0;
+        assert_eq!(outcome["steps"][0]["step"], "release_stock");
+        assert_eq!(outcome["steps"][0]["status"], "failed");
+        assert_eq!(outcome["steps"][1]["step"], "cancel_order");
+        assert_eq!(outcome["steps"][1]["status"], "succeeded");
+        assert_eq!(order_status()?.as_deref(), Some("PAYMENT_FAILED"));
+        assert_eq!(history()?, Some(vec!["PAYMENT_FAILED".to_string()]));
+
+        let outcome = crate::order_compensate("payment_failed", JsonB(event))?.0;
+        assert_eq!(outcome["steps"][0]["status"], "failed");
+        assert_eq!(outcome["steps"][1]["status"], "skipped");
+        let attempts = Spi::get_one::<i32>(
+            "SELECT attempts FROM COMPENSATION_LOG WHERE instance_id = 'i-1' AND step_name = 'release_stock'",
+        )?;
+        assert_eq!(attempts, Some(2));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn customer_is_told_how_each_failure_was_settled() -> Result<(), spi::Error> {
+        setup_order_tables()?;
+        crate::setup_order_compensation()?;
+        Spi::run(r#"
+            CREATE SCHEMA customer;
+            CREATE TABLE customer.customer_inbox (
+                id BIGSERIAL PRIMARY KEY,
+                customer_id INT NOT NULL,
+                event_type TEXT NOT NULL,
+                order_id INT,
+                payload JSONB NOT NULL
+            );
+            INSERT INTO "order".orders (customer_id, order_id, status) VALUES (1, 1, 'INVOICED');
+        "#)?;
+
+        let payment_failed = json!({ "instanceId": "i-1", "customer": { "CustomerId": 1 }, "orderId": 1 });
+        let outcome = crate::order_compensate("payment_failed", JsonB(payment_failed.clone()))?.0;
+        assert_eq!(outcome["steps"][2]["step"], "notify_customer");
+        assert_eq!(outcome["steps"][2]["status"], "succeeded");
+        // the redelivery retries release_stock only, so the customer is told once
+        crate::order_compensate("payment_failed", JsonB(payment_failed))?;
+
+        let stock_failed = json!({
+            "instanceId": "i-2", "customerCheckout": { "CustomerId": 1 }, "products": [{ "Id": 7, "Status": 1 }],
+        });
+        let outcome = crate::order_compensate("stock_failed", JsonB(stock_failed))?.0;
+        assert_eq!(outcome["steps"], json!([{ "step": "notify_customer", "status": "succeeded", "error": null }]));
+
+        let inbox = Spi::get_one::<JsonB>(r#"
+            SELECT jsonb_agg(jsonb_build_array(order_id, payload) ORDER BY id)
+            FROM customer.customer_inbox WHERE customer_id = 1 AND event_type = 'compensated'
+        "#)?.map(|inbox| inbox.0);
+        assert_eq!(inbox, Some(json!([
+            [1, { "instanceId": "i-1", "reason": "payment_failed",
+                  "steps": { "release_stock": "failed", "cancel_order": "succeeded" } }],
+            [null, { "instanceId": "i-2", "reason": "stock_failed", "products": [{ "Id": 7, "Status": 1 }] }],
+        ])));
+        // only the payment failure touched the order
+        assert_eq!(order_status()?.as_deref(), Some("PAYMENT_FAILED"));
+        assert_eq!(history()?, Some(vec!["PAYMENT_FAILED".to_string()]));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
@@ -1,10 +1,11 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
+    bgworkers::BackgroundWorkerBuilder, datum::TimestampWithTimeZone, default, error, log, pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde_json::{json, Value};
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -16,6 +17,8 @@ pgrx::pg_module_magic!();
 
 #[pg_extern]
 fn setup_payment() -> Result<(), spi::Error> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     let create_table_sql = r#"
         CREATE TABLE IF NOT EXISTS CHECKOUT (
             stream_id TEXT NOT NULL,
@@ -23,10 +26,25 @@ fn setup_payment() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             mark_status TEXT NOT NULL,
             customer_id TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
     Spi::run(create_table_sql)?;
+    marks::ensure_mark_columns("CHECKOUT")?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
+
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS PAYMENT_IDEMPOTENCY (
+            idempotency_key TEXT PRIMARY KEY,
+            result JSONB,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+    "#)?;
     Ok(())
 }
 
@@ -37,19 +55,34 @@ fn setup_payment() -> Result<(), spi::Error> {
 #[pg_extern]
 fn payment_add_checkout_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    customer_id: &str, mark_status: &str, db: &str
+    customer_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        customer_id.into(), mark_status.into(), db.into(),
+        customer_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
 
+#[pg_extern]
+fn payment_purge_marks(
+    before: TimestampWithTimeZone, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    marks::purge_marks_from("CHECKOUT", Some("payment"), Some(before), None, archive)
+}
+
+#[pg_extern]
+fn payment_purge_run_marks(
+    run_id: i64, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    marks::purge_marks_from("CHECKOUT", Some("payment"), None, Some(run_id), archive)
+}
+
 ////////////////////////////////////////
 // 3. Background Workers (BGWs)
 ////////////////////////////////////////
@@ -176,3 +209,516 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 7. Payment Provider Simulator
+////////////////////////////////////////
+
+// Currency ordinals, as serialized by PaymentMS
+static CURRENCIES: [&str; 4] = ["USD", "BRL", "DKK", "CHF"];
+
+static LATENCY_DISTRIBUTIONS: [&str; 4] = ["none", "constant", "uniform", "exponential"];
+
+/// Configures the embedded provider that replaces the PaymentProvider service.
+/// Latency is drawn from `latency_distribution` around `latency_mean_ms`
+/// (uniform draws from [0, 2 * mean]) and capped at `latency_max_ms` when it
+/// is positive. Card brand rules set with `set_payment_card_rule` override
+/// `approval_rate`.
+#[pg_extern]
+fn setup_payment_provider(
+    approval_rate: default!(f64, 1.0),
+    seed: default!(i64, 0),
+    latency_distribution: default!(&str, "'none'"),
+    latency_mean_ms: default!(f64, 0.0),
+    latency_max_ms: default!(f64, 0.0),
+) -> Result<(), spi::Error> {
+    if !(0.0..=1.0).contains(&approval_rate) {
+        error!("payment_ext: approval_rate must be between 0 and 1, got {approval_rate}");
+    }
+    if !LATENCY_DISTRIBUTIONS.contains(&latency_distribution) {
+        error!("payment_ext: unknown latency distribution `{latency_distribution}`, expected one of {LATENCY_DISTRIBUTIONS:?}");
+    }
+
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS PAYMENT_PROVIDER_SETTINGS (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            approval_rate FLOAT8 NOT NULL,
+            seed BIGINT NOT NULL,
+            latency_distribution TEXT NOT NULL,
+            latency_mean_ms FLOAT8 NOT NULL,
+            latency_max_ms FLOAT8 NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS PAYMENT_PROVIDER_CARD_RULES (
+            card_brand TEXT PRIMARY KEY,
+            approval_rate FLOAT8 NOT NULL CHECK (approval_rate BETWEEN 0 AND 1)
+        );
+        CREATE TABLE IF NOT EXISTS PAYMENT_PROVIDER_INTENTS (
+            idempotency_key TEXT PRIMARY KEY,
+            intent JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+    "#)?;
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO PAYMENT_PROVIDER_SETTINGS (id, approval_rate, seed, latency_distribution, latency_mean_ms, latency_max_ms)
+        VALUES (TRUE, $1, $2, $3, $4, $5)
+        ON CONFLICT (id) DO UPDATE SET
+            approval_rate = EXCLUDED.approval_rate,
+            seed = EXCLUDED.seed,
+            latency_distribution = EXCLUDED.latency_distribution,
+            latency_mean_ms = EXCLUDED.latency_mean_ms,
+            latency_max_ms = EXCLUDED.latency_max_ms;
+        "#,
+        &[
+            approval_rate.into(), seed.into(), latency_distribution.into(),
+            latency_mean_ms.into(), latency_max_ms.into(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Overrides the approval rate for one card brand, or drops the rule when
+/// `approval_rate` is NULL. Requires `setup_payment_provider()`.
+#[pg_extern]
+fn set_payment_card_rule(card_brand: &str, approval_rate: Option<f64>) -> Result<(), spi::Error> {
+    match approval_rate {
+        Some(approval_rate) => Spi::run_with_args(
+            r#"
+            INSERT INTO PAYMENT_PROVIDER_CARD_RULES (card_brand, approval_rate) VALUES ($1, $2)
+            ON CONFLICT (card_brand) DO UPDATE SET approval_rate = EXCLUDED.approval_rate;
+            "#,
+            &[card_brand.into(), approval_rate.into()],
+        ),
+        None => Spi::run_with_args(
+            "DELETE FROM PAYMENT_PROVIDER_CARD_RULES WHERE card_brand = $1",
+            &[card_brand.into()],
+        ),
+    }
+}
+
+/// SplitMix64, seeded per idempotency key so that an intent's outcome does
+/// not depend on the order in which concurrent payments reach the provider.
+struct SimulatorRng(u64);
+
+impl SimulatorRng {
+    fn new(seed: i64, key: &str) -> Self {
+        // FNV-1a of the key, mixed with the configured seed
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in key.bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        SimulatorRng(hash ^ seed as u64)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn simulated_latency_ms(rng: &mut SimulatorRng, distribution: &str, mean_ms: f64, max_ms: f64) -> f64 {
+    let latency_ms = match distribution {
+        "constant" => mean_ms,
+        "uniform" => rng.next_f64() * 2.0 * mean_ms,
+        "exponential" => -mean_ms * (1.0 - rng.next_f64()).ln(),
+        _ => 0.0,
+    };
+    if max_ms > 0.0 { latency_ms.min(max_ms) } else { latency_ms }
+}
+
+/// Authorizes a PaymentIntentCreateOptions payload the way PaymentProvider
+/// does and returns the PaymentIntent. Repeated calls with the same
+/// IdempotencyKey return the first intent. The card brand is read from an
+/// optional `CardBrand` field. Requires `setup_payment_provider()`.
+#[pg_extern]
+fn payment_authorize(intent: JsonB) -> Result<JsonB, spi::Error> {
+    let options = intent.0;
+    let idempotency_key = match options["IdempotencyKey"].as_str() {
+        Some(key) if !key.is_empty() => key.to_string(),
+        _ => error!("payment_ext: payment intent has no IdempotencyKey"),
+    };
+
+    let existing = Spi::get_one_with_args::<JsonB>(
+        "SELECT intent FROM PAYMENT_PROVIDER_INTENTS WHERE idempotency_key = $1",
+        &[idempotency_key.clone().into()],
+    );
+    match existing {
+        Ok(Some(existing)) => return Ok(existing),
+        Err(spi::Error::InvalidPosition) | Ok(None) => {}
+        Err(e) => return Err(e),
+    }
+
+    let card_brand = options["CardBrand"].as_str().unwrap_or_default().to_string();
+    let settings_sql = r#"
+        SELECT coalesce(r.approval_rate, s.approval_rate) AS approval_rate, s.seed,
+               s.latency_distribution, s.latency_mean_ms, s.latency_max_ms
+        FROM PAYMENT_PROVIDER_SETTINGS s
+        LEFT JOIN PAYMENT_PROVIDER_CARD_RULES r ON r.card_brand = $1;
+    "#;
+    let (approval_rate, seed, distribution, mean_ms, max_ms) = Spi::connect(|client| {
+        let row = client.select(settings_sql, Some(1), &[card_brand.into()])?.first();
+        Ok::<_, spi::Error>((
+            row.get_by_name::<f64, _>("approval_rate")?.unwrap_or(1.0),
+            row.get_by_name::<i64, _>("seed")?.unwrap_or(0),
+            row.get_by_name::<String, _>("latency_distribution")?.unwrap_or_default(),
+            row.get_by_name::<f64, _>("latency_mean_ms")?.unwrap_or(0.0),
+            row.get_by_name::<f64, _>("latency_max_ms")?.unwrap_or(0.0),
+        ))
+    })?;
+
+    let mut rng = SimulatorRng::new(seed, &idempotency_key);
+    let latency_ms = simulated_latency_ms(&mut rng, &distribution, mean_ms, max_ms);
+    if latency_ms > 0.0 {
+        // pg_sleep keeps the backend responsive to cancellation
+        Spi::run_with_args("SELECT pg_sleep($1)", &[(latency_ms / 1000.0).into()])?;
+    }
+    let status = if rng.next_f64() < approval_rate { "succeeded" } else { "canceled" };
+
+    let currency = match &options["Currency"] {
+        Value::Number(ordinal) => CURRENCIES.get(ordinal.as_u64().unwrap_or(0) as usize).copied().unwrap_or("USD").to_string(),
+        Value::String(currency) => currency.clone(),
+        _ => CURRENCIES[0].to_string(),
+    };
+    let created = Spi::get_one::<i32>("SELECT extract(epoch FROM now())::INT")?.unwrap_or_default();
+
+    let payment_intent = json!({
+        "id": format!("pi_{:016x}", rng.next_u64()),
+        "amount": options["Amount"].as_f64().unwrap_or_default(),
+        "status": status,
+        "client_secret": "",
+        "currency": currency,
+        "customer": options["Customer"].as_str().unwrap_or_default(),
+        "confirmation_method": "automatic",
+        "created": created,
+    });
+
+    // the first intent stored under a key wins a race between concurrent calls
+    let stored = Spi::get_one_with_args::<JsonB>(
+        r#"
+        WITH inserted AS (
+            INSERT INTO PAYMENT_PROVIDER_INTENTS (idempotency_key, intent) VALUES ($1, $2)
+            ON CONFLICT (idempotency_key) DO NOTHING
+            RETURNING intent
+        )
+        SELECT intent FROM inserted
+        UNION ALL
+        SELECT intent FROM PAYMENT_PROVIDER_INTENTS WHERE idempotency_key = $1
+        LIMIT 1;
+        "#,
+        &[idempotency_key.into(), JsonB(payment_intent.clone()).into()],
+    )?;
+    Ok(stored.unwrap_or(JsonB(payment_intent)))
+}
+
+////////////////////////////////////////
+// 8. Invoice Payment
+////////////////////////////////////////
+
+const CHECKOUT_STREAM_ID: &str = "TransactionMark_CUSTOMER_SESSION";
+
+fn insert_payment_line(
+    customer_id: i32, order_id: i32, sequential: i32, payment_type: &str,
+    installments: i32, value: f64, status: &str,
+) -> Result<(), spi::Error> {
+    let insert_sql = r#"
+        INSERT INTO payment.order_payments (customer_id, order_id, sequential, type, installments, value, status, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6::REAL, $7, now());
+    "#;
+    Spi::run_with_args(insert_sql, &[
+        customer_id.into(), order_id.into(), sequential.into(), payment_type.into(),
+        installments.into(), value.into(), status.into(),
+    ])
+}
+
+/// Processes an InvoiceIssued event as PaymentService does: records the
+/// order_payments lines and card details, then publishes PaymentConfirmed or
+/// PaymentFailed, the latter with a NOT_ACCEPTED checkout mark. With
+/// `authorize` the card is approved by `payment_authorize()`, otherwise every
+/// payment succeeds. A replayed invoice, identified by its instanceId and
+/// invoiceNumber, returns the result recorded the first time and charges
+/// nothing; a concurrent replay waits for the first to commit.
+///
+/// A confirmed payment writes no mark, as in PaymentService: the mark tables
+/// fold a checkout to its latest mark, so a payment SUCCESS would report the
+/// checkout completed while shipment is still pending. Shipment's SUCCESS
+/// marks the outcome.
+#[pg_extern]
+fn payment_process_invoice(invoice: JsonB, authorize: default!(bool, false)) -> Result<JsonB, spi::Error> {
+    let invoice = invoice.0;
+    let customer = &invoice["customer"];
+    let instance_id = invoice["instanceId"].as_str().unwrap_or_default();
+    let invoice_number = invoice["invoiceNumber"].as_str().unwrap_or_default();
+    let (Some(customer_id), Some(order_id)) = (customer["CustomerId"].as_i64(), invoice["orderId"].as_i64()) else {
+        error!("payment_ext: InvoiceIssued payload has no customer or order id");
+    };
+    let (customer_id, order_id) = (customer_id as i32, order_id as i32);
+    let total_invoice = invoice["totalInvoice"].as_f64().unwrap_or_default();
+    let idempotency_key = format!("{instance_id}/{invoice_number}");
+
+    // the key's row lock makes a concurrent replay block until this transaction ends
+    let claimed = Spi::get_one_with_args::<String>(
+        r#"
+        INSERT INTO PAYMENT_IDEMPOTENCY (idempotency_key) VALUES ($1)
+        ON CONFLICT (idempotency_key) DO NOTHING
+        RETURNING idempotency_key;
+        "#,
+        &[idempotency_key.clone().into()],
+    );
+    match claimed {
+        Ok(Some(_)) => {}
+        Err(spi::Error::InvalidPosition) | Ok(None) => {
+            let prior = Spi::get_one_with_args::<JsonB>(
+                "SELECT result FROM PAYMENT_IDEMPOTENCY WHERE idempotency_key = $1",
+                &[idempotency_key.into()],
+            )?;
+            return Ok(prior.unwrap_or(JsonB(Value::Null)));
+        }
+        Err(e) => return Err(e),
+    }
+
+    let status = if authorize {
+        let intent = payment_authorize(JsonB(json!({
+            "Customer": customer_id.to_string(),
+            "Amount": total_invoice,
+            "IdempotencyKey": invoice_number,
+            "CardBrand": customer["CardBrand"],
+        })))?;
+        if intent.0["status"] == "succeeded" { "succeeded" } else { "requires_payment_method" }
+    } else {
+        "succeeded"
+    };
+
+    let payment_type = customer["PaymentType"].as_str().unwrap_or_default();
+    let mut sequential = 1;
+    if payment_type == "CREDIT_CARD" || payment_type == "DEBIT_CARD" {
+        let installments = customer["Installments"].as_i64().unwrap_or(1) as i32;
+        insert_payment_line(customer_id, order_id, sequential, payment_type, installments, total_invoice, status)?;
+
+        let insert_card_sql = r#"
+            INSERT INTO payment.order_payment_cards
+                (customer_id, order_id, sequential, card_number, card_holder_name, card_expiration, card_brand)
+            VALUES ($1, $2, $3, $4, $5, to_timestamp($6, 'MMYY'), $7);
+        "#;
+        Spi::run_with_args(insert_card_sql, &[
+            customer_id.into(), order_id.into(), sequential.into(),
+            customer["CardNumber"].as_str().unwrap_or_default().into(),
+            customer["CardHolderName"].as_str().unwrap_or_default().into(),
+            customer["CardExpiration"].as_str().unwrap_or_default().into(),
+            customer["CardBrand"].as_str().unwrap_or_default().into(),
+        ])?;
+        sequential += 1;
+    }
+    if payment_type == "BOLETO" {
+        insert_payment_line(customer_id, order_id, sequential, "BOLETO", 1, total_invoice, status)?;
+        sequential += 1;
+    }
+
+    // vouchers apply only if the payment succeeded
+    let items = invoice["items"].as_array().cloned().unwrap_or_default();
+    if status == "succeeded" {
+        for item in &items {
+            let voucher = item["total_incentive"].as_f64().unwrap_or_default();
+            if voucher > 0.0 {
+                insert_payment_line(customer_id, order_id, sequential, "VOUCHER", 1, voucher, status)?;
+                sequential += 1;
+            }
+        }
+    }
+
+    let (channel, event) = if status == "succeeded" {
+        let date = Spi::get_one::<JsonB>("SELECT to_jsonb(now())")?.map(|date| date.0).unwrap_or(Value::Null);
+        ("payment_confirmed", json!({
+            "customer": customer,
+            "orderId": order_id,
+            "totalAmount": total_invoice,
+            "items": items,
+            "date": date,
+            "instanceId": instance_id,
+        }))
+    } else {
+        ("payment_failed", json!({
+            "status": status,
+            "customer": customer,
+            "orderId": order_id,
+            "items": items,
+            "totalAmount": total_invoice,
+            "instanceId": instance_id,
+        }))
+    };
+    marks::notify(channel, &event)?;
+
+    if status != "succeeded" {
+        payment_add_checkout_transaction_mark(
+            CHECKOUT_STREAM_ID, instance_id, "CUSTOMER_SESSION",
+            &customer_id.to_string(), "NOT_ACCEPTED", "payment", None,
+        )?;
+    }
+
+    let result = json!({ "status": status, "channel": channel, "event": event });
+    Spi::run_with_args(
+        "UPDATE PAYMENT_IDEMPOTENCY SET result = $2 WHERE idempotency_key = $1",
+        &[idempotency_key.into(), JsonB(result.clone()).into()],
+    )?;
+    Ok(JsonB(result))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use crate::{simulated_latency_ms, SimulatorRng};
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+    use pgrx::JsonB;
+    use serde_json::{json, Value};
+
+    fn authorize(key: &str, card_brand: &str) -> Result<Value, spi::Error> {
+        let intent = json!({ "Customer": "1", "Amount": 10.0, "IdempotencyKey": key, "CardBrand": card_brand });
+        Ok(crate::payment_authorize(JsonB(intent))?.0)
+    }
+
+    fn setup_payment_tables() -> Result<(), spi::Error> {
+        Spi::run(r#"
+            CREATE SCHEMA payment;
+            CREATE TABLE payment.order_payments (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                sequential INT NOT NULL,
+                type TEXT NOT NULL,
+                installments INT NOT NULL,
+                value REAL NOT NULL,
+                status TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL,
+                PRIMARY KEY (customer_id, order_id, sequential)
+            );
+            CREATE TABLE payment.order_payment_cards (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                sequential INT NOT NULL,
+                card_number TEXT NOT NULL,
+                card_holder_name TEXT NOT NULL,
+                card_expiration TIMESTAMPTZ NOT NULL,
+                card_brand TEXT NOT NULL
+            );
+        "#)?;
+        crate::setup_payment()
+    }
+
+    fn invoice() -> JsonB {
+        JsonB(json!({
+            "customer": {
+                "CustomerId": 1, "PaymentType": "CREDIT_CARD", "Installments": 2,
+                "CardNumber": "4111", "CardHolderName": "A", "CardExpiration": "1230", "CardBrand": "VISA",
+            },
+            "orderId": 3,
+            "invoiceNumber": "1-20260101-3",
+            "totalInvoice": 30.0,
+            "items": [{ "total_incentive": 2.0 }],
+            "instanceId": "i-1",
+        }))
+    }
+
+    fn count(sql: &str) -> Result<Option<i64>, spi::Error> {
+        Spi::get_one(sql)
+    }
+
+    #[pg_test]
+    fn replayed_invoice_returns_prior_result_and_charges_once() -> Result<(), spi::Error> {
+        setup_payment_tables()?;
+        let first = crate::payment_process_invoice(invoice(), false)?.0;
+        assert_eq!(first["channel"], "payment_confirmed");
+        assert_eq!(count("SELECT count(*) FROM payment.order_payments")?, Some(2));
+
+        let replay = crate::payment_process_invoice(invoice(), false)?.0;
+        assert_eq!(replay, first);
+        assert_eq!(count("SELECT count(*) FROM payment.order_payments")?, Some(2));
+        assert_eq!(count("SELECT count(*) FROM payment.order_payment_cards")?, Some(1));
+        assert_eq!(count("SELECT count(*) FROM CHECKOUT")?, Some(0));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn replayed_failed_invoice_marks_once() -> Result<(), spi::Error> {
+        setup_payment_tables()?;
+        crate::setup_payment_provider(0.0, 0, "none", 0.0, 0.0)?;
+        let first = crate::payment_process_invoice(invoice(), true)?.0;
+        assert_eq!(first["channel"], "payment_failed");
+
+        assert_eq!(crate::payment_process_invoice(invoice(), true)?.0, first);
+        // a failed payment records the card line but no voucher
+        assert_eq!(count("SELECT count(*) FROM payment.order_payments")?, Some(1));
+        assert_eq!(count("SELECT count(*) FROM CHECKOUT WHERE mark_status = 'NOT_ACCEPTED'")?, Some(1));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn same_key_draws_same_latency_and_decision() -> Result<(), spi::Error> {
+        let draw = |key: &str| {
+            let mut rng = SimulatorRng::new(42, key);
+            (simulated_latency_ms(&mut rng, "exponential", 5.0, 0.0), rng.next_f64())
+        };
+        assert_eq!(draw("k-1"), draw("k-1"));
+        assert_ne!(draw("k-1"), draw("k-2"));
+        assert!(simulated_latency_ms(&mut SimulatorRng::new(42, "k-1"), "uniform", 50.0, 1.0) <= 1.0);
+
+        // Forgetting the stored intent draws it again, with the same outcome
+        crate::setup_payment_provider(0.5, 42, "none", 0.0, 0.0)?;
+        let first = authorize("k-1", "VISA")?;
+        Spi::run("DELETE FROM PAYMENT_PROVIDER_INTENTS")?;
+        let second = authorize("k-1", "VISA")?;
+        assert_eq!(first["status"], second["status"]);
+        assert_eq!(first["id"], second["id"]);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn card_rules_override_approval_rate() -> Result<(), spi::Error> {
+        crate::setup_payment_provider(1.0, 0, "none", 0.0, 0.0)?;
+        crate::set_payment_card_rule("VISA", Some(0.0))?;
+        for i in 0..5 {
+            assert_eq!(authorize(&format!("visa-{i}"), "VISA")?["status"], "canceled");
+            assert_eq!(authorize(&format!("master-{i}"), "MASTERCARD")?["status"], "succeeded");
+        }
+
+        crate::set_payment_card_rule("VISA", None)?;
+        assert_eq!(authorize("visa-5", "VISA")?["status"], "succeeded");
+        Ok(())
+    }
+
+    #[pg_test]
+    fn replayed_key_returns_stored_intent() -> Result<(), spi::Error> {
+        crate::setup_payment_provider(1.0, 0, "none", 0.0, 0.0)?;
+        let first = authorize("k-1", "VISA")?;
+        assert_eq!(first["status"], "succeeded");
+
+        crate::setup_payment_provider(0.0, 0, "none", 0.0, 0.0)?;
+        assert_eq!(authorize("k-1", "VISA")?, first);
+        assert_eq!(authorize("k-2", "VISA")?["status"], "canceled");
+        let intents = Spi::get_one::<i64>("SELECT count(*) FROM PAYMENT_PROVIDER_INTENTS")?;
+        assert_eq!(intents, Some(2));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
@@ -1,5 +1,9 @@
+use marks::notify;
+use pgrx::datum::TryFromDatumError;
 use pgrx::prelude::*;
 use pgrx::spi::{Spi, SpiError};
+use serde_json::{json, Value};
+use std::fmt;
 
 ::pgrx::pg_module_magic!();
 
@@ -9,6 +13,8 @@ use pgrx::spi::{Spi, SpiError};
 
 #[pg_extern]
 fn setup_product() -> Result<(), SpiError> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     let create_price_sql = r#"
         CREATE TABLE IF NOT EXISTS PRICEUPDATE (
             stream_id TEXT NOT NULL,
@@ -16,11 +22,18 @@ fn setup_product() -> Result<(), SpiError> {
             transaction_type TEXT NOT NULL,
             seller_id TEXT NOT NULL,
             mark_status TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
 
     Spi::run(create_price_sql)?;
+    marks::ensure_mark_columns("PRICEUPDATE")?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
     Ok(())
 }
 
@@ -31,16 +44,419 @@ fn setup_product() -> Result<(), SpiError> {
 #[pg_extern]
 fn product_add_price_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    seller_id: &str, mark_status: &str, db: &str
+    seller_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), SpiError> {
     let insert_sql = r#"
-        INSERT INTO PRICEUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO PRICEUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
 
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        seller_id.into(), mark_status.into(), db.into(),
+        seller_id.into(), mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
+
+#[pg_extern]
+fn product_purge_marks(
+    before: TimestampWithTimeZone, archive: default!(bool, false)
+) -> Result<i64, SpiError> {
+    marks::purge_marks_from("PRICEUPDATE", Some("product"), Some(before), None, archive)
+}
+
+#[pg_extern]
+fn product_purge_run_marks(
+    run_id: i64, archive: default!(bool, false)
+) -> Result<i64, SpiError> {
+    marks::purge_marks_from("PRICEUPDATE", Some("product"), None, Some(run_id), archive)
+}
+
+////////////////////////////////////////
+// 3. Change-Data Publisher
+////////////////////////////////////////
+
+/// Installs the trigger that publishes `price_changes` and `product_changes`
+/// from the committed rows of product.products. Requires the table to exist.
+#[pg_extern]
+fn setup_product_change_publisher() -> Result<(), SpiError> {
+    let create_trigger_sql = r#"
+        DROP TRIGGER IF EXISTS product_change_publisher ON product.products;
+        CREATE TRIGGER product_change_publisher
+        AFTER UPDATE ON product.products
+        FOR EACH ROW
+        EXECUTE FUNCTION product_publish_changes();
+    "#;
+
+    Spi::run(create_trigger_sql)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum PublishError {
+    MissingTuple,
+    MissingInstanceId,
+    Datum(TryFromDatumError),
+    Spi(SpiError),
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::MissingTuple => write!(f, "trigger fired without OLD and NEW rows"),
+            PublishError::MissingInstanceId => write!(
+                f,
+                "price updated without product.instance_id set; use product_update_price() or SET LOCAL product.instance_id"
+            ),
+            PublishError::Datum(e) => write!(f, "cannot read product column: {e}"),
+            PublishError::Spi(e) => write!(f, "cannot publish product change: {e}"),
+        }
+    }
+}
+
+impl From<TryFromDatumError> for PublishError {
+    fn from(e: TryFromDatumError) -> Self {
+        PublishError::Datum(e)
+    }
+}
+
+impl From<SpiError> for PublishError {
+    fn from(e: SpiError) -> Self {
+        PublishError::Spi(e)
+    }
+}
+
+// Text attributes carried by ProductUpdated; freight_value is compared apart
+static PRODUCT_ATTRIBUTES: [&str; 6] = ["name", "sku", "category", "description", "status", "version"];
+
+/// Publishes ProductUpdated when any attribute other than the price changed,
+/// as ProductMS does for a product update, and PriceUpdated when only the price
+/// did. NOTIFY is delivered on commit, so a rolled back update publishes
+/// nothing. PriceUpdated takes its instanceId from the `product.instance_id`
+/// setting, and a price update without it fails, since the cart's PRICE_UPDATE
+/// mark would otherwise never match the driver's transaction.
+#[pg_trigger]
+fn product_publish_changes<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PublishError> {
+    let (old, new) = match (trigger.old(), trigger.new()) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Err(PublishError::MissingTuple),
+    };
+
+    let mut attributes_changed = old.get_by_name::<f32>("freight_value")? != new.get_by_name::<f32>("freight_value")?;
+    for attribute in PRODUCT_ATTRIBUTES {
+        attributes_changed |= old.get_by_name::<String>(attribute)? != new.get_by_name::<String>(attribute)?;
+    }
+    let price_changed = old.get_by_name::<f32>("price")? != new.get_by_name::<f32>("price")?;
+
+    let seller_id = new.get_by_name::<i32>("seller_id")?;
+    let product_id = new.get_by_name::<i32>("product_id")?;
+    let price = new.get_by_name::<f32>("price")?;
+    let version = new.get_by_name::<String>("version")?;
+
+    if attributes_changed {
+        notify("product_changes", &json!({
+            "seller_id": seller_id,
+            "product_id": product_id,
+            "name": new.get_by_name::<String>("name")?,
+            "sku": new.get_by_name::<String>("sku")?,
+            "category": new.get_by_name::<String>("category")?,
+            "description": new.get_by_name::<String>("description")?,
+            "price": price,
+            "freight_value": new.get_by_name::<f32>("freight_value")?,
+            "status": new.get_by_name::<String>("status")?,
+            "version": version,
+        }))?;
+    } else if price_changed {
+        let instance_id = Spi::get_one::<String>("SELECT nullif(current_setting('product.instance_id', true), '')")?
+            .ok_or(PublishError::MissingInstanceId)?;
+        notify("price_changes", &json!({
+            "seller_id": seller_id,
+            "product_id": product_id,
+            "price": price,
+            "version": version,
+            "instanceId": instance_id,
+        }))?;
+    }
+
+    Ok(Some(new))
+}
+
+/// ProductService.ProcessPriceUpdate on top of the publisher: sets the price
+/// when `version` still matches, with `product.instance_id` set for the
+/// trigger. ProductMS publishes PriceUpdated even when the version does not
+/// match, as cart items may still hold it; no row changes then and the trigger
+/// stays silent, so the event is published here instead. The same goes for an
+/// unchanged price. Returns whether the price changed.
+#[pg_extern]
+fn product_update_price(
+    seller_id: i32, product_id: i32, price: f32, version: &str, instance_id: &str,
+) -> Result<bool, SpiError> {
+    Spi::run_with_args(
+        "SELECT set_config('product.instance_id', $1, true)",
+        &[instance_id.into()],
+    )?;
+    let updated = Spi::get_one_with_args::<bool>(
+        r#"
+        UPDATE product.products SET price = $3, updated_at = now()
+        WHERE seller_id = $1 AND product_id = $2 AND version = $4 AND price IS DISTINCT FROM $3
+        RETURNING TRUE;
+        "#,
+        &[seller_id.into(), product_id.into(), price.into(), version.into()],
+    );
+    let updated = match updated {
+        Err(SpiError::InvalidPosition) => false,
+        other => other?.unwrap_or(false),
+    };
+
+    if !updated {
+        notify("price_changes", &json!({
+            "seller_id": seller_id,
+            "product_id": product_id,
+            "price": price,
+            "version": version,
+            "instanceId": instance_id,
+        }))?;
+    }
+    Ok(updated)
+}
+
+////////////////////////////////////////
+// 4. Price History
+////////////////////////////////////////
+
+/// Creates PRICE_HISTORY and the trigger that keeps it. Products without an
+/// entry get one from now on with their current price, as their earlier prices
+/// are unknown. Requires product.products.
+#[pg_extern]
+fn setup_product_price_history() -> Result<(), SpiError> {
+    let create_history_sql = r#"
+        CREATE TABLE IF NOT EXISTS PRICE_HISTORY (
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            version TEXT NOT NULL,
+            price REAL NOT NULL,
+            valid_from TIMESTAMPTZ NOT NULL,
+            valid_to TIMESTAMPTZ,
+            PRIMARY KEY (seller_id, product_id, valid_from)
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS price_history_current_idx
+            ON PRICE_HISTORY (seller_id, product_id) WHERE valid_to IS NULL;
+    "#;
+    let seed_history_sql = r#"
+        LOCK TABLE product.products IN SHARE MODE;
+
+        INSERT INTO PRICE_HISTORY (seller_id, product_id, version, price, valid_from)
+        SELECT p.seller_id, p.product_id, p.version, p.price, now()
+        FROM product.products p
+        WHERE NOT EXISTS (
+            SELECT 1 FROM PRICE_HISTORY h WHERE h.seller_id = p.seller_id AND h.product_id = p.product_id
+        );
+
+        DROP TRIGGER IF EXISTS product_price_recorder ON product.products;
+        CREATE TRIGGER product_price_recorder
+        AFTER INSERT OR UPDATE OF price, version ON product.products
+        FOR EACH ROW
+        EXECUTE FUNCTION product_record_price();
+    "#;
+
+    Spi::run(create_history_sql)?;
+    Spi::run(seed_history_sql)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum PriceHistoryError {
+    MissingTuple,
+    Datum(TryFromDatumError),
+    Spi(SpiError),
+}
+
+impl fmt::Display for PriceHistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceHistoryError::MissingTuple => write!(f, "trigger fired without a NEW row"),
+            PriceHistoryError::Datum(e) => write!(f, "cannot read product column: {e}"),
+            PriceHistoryError::Spi(e) => write!(f, "cannot record product price: {e}"),
+        }
+    }
+}
+
+impl From<TryFromDatumError> for PriceHistoryError {
+    fn from(e: TryFromDatumError) -> Self {
+        PriceHistoryError::Datum(e)
+    }
+}
+
+impl From<SpiError> for PriceHistoryError {
+    fn from(e: SpiError) -> Self {
+        PriceHistoryError::Spi(e)
+    }
+}
+
+/// Closes the product's open PRICE_HISTORY entry and opens one for the new
+/// price or version, both at the wall-clock time of the change. The product
+/// row lock orders concurrent changes, but their transactions may have started
+/// the other way round, so the transaction time would not be monotonic.
+#[pg_trigger]
+fn product_record_price<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PriceHistoryError> {
+    let Some(new) = trigger.new() else {
+        return Err(PriceHistoryError::MissingTuple);
+    };
+    if let Some(old) = trigger.old() {
+        if old.get_by_name::<f32>("price")? == new.get_by_name::<f32>("price")?
+            && old.get_by_name::<String>("version")? == new.get_by_name::<String>("version")?
+        {
+            return Ok(Some(new));
+        }
+    }
+
+    let seller_id = new.get_by_name::<i32>("seller_id")?;
+    let product_id = new.get_by_name::<i32>("product_id")?;
+    let changed_at = Spi::get_one::<TimestampWithTimeZone>("SELECT clock_timestamp()")?;
+    let close_price_sql = r#"
+        UPDATE PRICE_HISTORY SET valid_to = $3
+        WHERE seller_id = $1 AND product_id = $2 AND valid_to IS NULL;
+    "#;
+    let open_price_sql = r#"
+        INSERT INTO PRICE_HISTORY (seller_id, product_id, version, price, valid_from)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (seller_id, product_id, valid_from) DO UPDATE SET
+            version = EXCLUDED.version, price = EXCLUDED.price, valid_to = NULL;
+    "#;
+    Spi::run_with_args(close_price_sql, &[seller_id.into(), product_id.into(), changed_at.into()])?;
+    Spi::run_with_args(open_price_sql, &[
+        seller_id.into(),
+        product_id.into(),
+        new.get_by_name::<String>("version")?.into(),
+        new.get_by_name::<f32>("price")?.into(),
+        changed_at.into(),
+    ])?;
+
+    Ok(Some(new))
+}
+
+/// The price the product had at `ts`, optionally only if it was at `version`
+/// then. NULL when the product had no price recorded at that time.
+#[pg_extern]
+fn product_price_at(
+    seller_id: i32, product_id: i32, ts: TimestampWithTimeZone,
+    version: default!(Option<&str>, "NULL"),
+) -> Result<Option<f32>, SpiError> {
+    let price_at_sql = r#"
+        SELECT price FROM PRICE_HISTORY
+        WHERE seller_id = $1 AND product_id = $2
+          AND valid_from <= $3 AND (valid_to IS NULL OR $3 < valid_to)
+          AND ($4::TEXT IS NULL OR version = $4);
+    "#;
+    match Spi::get_one_with_args::<f32>(price_at_sql, &[seller_id.into(), product_id.into(), ts.into(), version.into()]) {
+        Err(SpiError::InvalidPosition) => Ok(None),
+        other => other,
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::{Spi, SpiError};
+
+    fn setup_product_tables() -> Result<(), SpiError> {
+        Spi::run(r#"
+            CREATE SCHEMA product;
+            CREATE TABLE product.products (
+                seller_id INT NOT NULL,
+                product_id INT NOT NULL,
+                name TEXT NOT NULL,
+                version TEXT NOT NULL,
+                price REAL NOT NULL,
+                PRIMARY KEY (seller_id, product_id)
+            );
+            INSERT INTO product.products VALUES (1, 1, 'lamp', '0', 10);
+        "#)?;
+        crate::setup_product_price_history()
+    }
+
+    fn clock() -> Result<TimestampWithTimeZone, SpiError> {
+        Ok(Spi::get_one::<TimestampWithTimeZone>("SELECT clock_timestamp()")?.expect("clock_timestamp() is not NULL"))
+    }
+
+    fn history_len(open_only: bool) -> Result<i64, SpiError> {
+        let count = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM PRICE_HISTORY WHERE seller_id = 1 AND product_id = 1 AND (NOT $1 OR valid_to IS NULL)",
+            &[open_only.into()],
+        )?;
+        Ok(count.unwrap_or(0))
+    }
+
+    #[pg_test]
+    fn price_changes_close_the_open_entry() -> Result<(), SpiError> {
+        setup_product_tables()?;
+        assert_eq!(history_len(false)?, 1);
+
+        Spi::run("UPDATE product.products SET price = 12 WHERE product_id = 1")?;
+        Spi::run("UPDATE product.products SET version = '1' WHERE product_id = 1")?;
+        assert_eq!(history_len(false)?, 3);
+        assert_eq!(history_len(true)?, 1);
+
+        // unchanged price and version, or other columns, record nothing
+        Spi::run("UPDATE product.products SET price = 12, name = 'desk lamp' WHERE product_id = 1")?;
+        assert_eq!(history_len(false)?, 3);
+
+        let gaps = Spi::get_one::<i64>(r#"
+            SELECT count(*) FROM (
+                SELECT valid_to, lead(valid_from) OVER (ORDER BY valid_from) AS next_from
+                FROM PRICE_HISTORY WHERE seller_id = 1 AND product_id = 1
+            ) h
+            WHERE valid_to IS DISTINCT FROM next_from;
+        "#)?;
+        assert_eq!(gaps, Some(0));
+
+        Spi::run("INSERT INTO product.products VALUES (1, 2, 'chair', '0', 30)")?;
+        let opened = Spi::get_one::<f32>("SELECT price FROM PRICE_HISTORY WHERE product_id = 2 AND valid_to IS NULL")?;
+        assert_eq!(opened, Some(30.0));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn price_at_before_between_and_after_changes() -> Result<(), SpiError> {
+        setup_product_tables()?;
+        let before = Spi::get_one::<TimestampWithTimeZone>("SELECT now() - interval '1 second'")?
+            .expect("now() is not NULL");
+        let seeded = clock()?;
+        Spi::run("UPDATE product.products SET price = 12 WHERE product_id = 1")?;
+        let between = clock()?;
+        Spi::run("UPDATE product.products SET price = 15, version = '1' WHERE product_id = 1")?;
+        let after = clock()?;
+
+        assert_eq!(crate::product_price_at(1, 1, before, None)?, None);
+        assert_eq!(crate::product_price_at(1, 1, seeded, None)?, Some(10.0));
+        assert_eq!(crate::product_price_at(1, 1, between, None)?, Some(12.0));
+        assert_eq!(crate::product_price_at(1, 1, after, None)?, Some(15.0));
+
+        assert_eq!(crate::product_price_at(1, 1, between, Some("0"))?, Some(12.0));
+        assert_eq!(crate::product_price_at(1, 1, after, Some("0"))?, None);
+        assert_eq!(crate::product_price_at(1, 1, after, Some("1"))?, Some(15.0));
+        assert_eq!(crate::product_price_at(1, 2, after, None)?, None);
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
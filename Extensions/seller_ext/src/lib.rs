@@ -1,10 +1,11 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
+    bgworkers::{BackgroundWorker, BackgroundWorkerBuilder}, default, log, pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde_json::Value;
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -30,20 +31,28 @@ const BGW_ID_OFFSET: i32 = 7;  // Ensure unique BGW IDs (7-10)
 // 2. Start BGWs
 ////////////////////////////////////////
 
+/// Starts the listeners for order events. By default each event is relayed to
+/// SellerMS on its out channel; with `forward => false` it is applied to the
+/// order entries here instead, which requires `setup_seller_dashboard()`. The
+/// modes are exclusive, since SellerMS would insert the order entries applied
+/// here again and hit their primary key. Either way the dashboard totals
+/// follow the order entries through the triggers `setup_seller_dashboard()`
+/// installs.
 #[pg_extern]
-fn seller_listen_to_changes() -> Result<(), String> {
+fn seller_listen_to_changes(forward: default!(bool, true)) -> Result<(), String> {
     for (i, _) in CHANNELS.iter().enumerate() {
-        spawn_listener(i as i32 + BGW_ID_OFFSET)?;
+        spawn_listener(i as i32 + BGW_ID_OFFSET, forward)?;
     }
     Ok(())
 }
 
-fn spawn_listener(id: i32) -> Result<(), String> {
+fn spawn_listener(id: i32, forward: bool) -> Result<(), String> {
     BackgroundWorkerBuilder::new("seller_listener")
         .set_library("seller_ext")
         .set_function("listen_bgworker")
         .enable_spi_access()
         .set_argument(id.into_datum())
+        .set_extra(if forward { "forward" } else { "" })
         .load_dynamic();
     Ok(())
 }
@@ -87,7 +96,12 @@ pub extern "C" fn listen_bgworker(arg: pg_sys::Datum) {
 
 fn run_bgworker(id: i32) {
     let (in_channel, out_channel) = CHANNELS[(id - BGW_ID_OFFSET) as usize];
-    log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    let forward = BackgroundWorker::get_extra() == "forward";
+    if forward {
+        log!("BGW {id}: Starting, listening on `{in_channel}`, forwarding to `{out_channel}`");
+    } else {
+        log!("BGW {id}: Starting, listening on `{in_channel}`");
+    }
 
     loop {
         pgrx::check_for_interrupts!();
@@ -113,16 +127,20 @@ fn run_bgworker(id: i32) {
                             unsafe { pg_sys::StartTransactionCommand(); }
                             unsafe { pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot()); }
 
-                            // Notify via SPI
-                            let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
-                            let spi_result = Spi::run_with_args(&notify_sql, &[payload.clone().into()]);
+                            // Notify via SPI, or apply to the dashboard
+                            let spi_result = if forward {
+                                let notify_sql = format!("SELECT pg_notify('{out_channel}', $1)");
+                                Spi::run_with_args(&notify_sql, &[payload.clone().into()])
+                            } else {
+                                apply_order_event(in_channel, &payload)
+                            };
 
                             // Cleanup transaction
                             unsafe { pg_sys::PopActiveSnapshot(); }
                             unsafe { pg_sys::CommitTransactionCommand(); }
 
                             if let Err(e) = spi_result {
-                                log!("BGW {id}: SPI error while NOTIFY: {e}");
+                                log!("BGW {id}: SPI error while handling `{in_channel}` event: {e}");
                             }
                         }
                         Ok(None) => break,
@@ -141,3 +159,405 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 5. Seller Dashboard
+////////////////////////////////////////
+
+// Order entries counted by the dashboard, as in SellerMS' OrderSellerView
+const OPEN_STATUSES_SQL: &str = "('INVOICED', 'PAYMENT_PROCESSED', 'READY_FOR_SHIPMENT', 'IN_TRANSIT')";
+
+// ShipmentStatus and PackageStatus names by ordinal, as serialized by the C# services
+static SHIPMENT_STATUSES: [&str; 3] = ["approved", "delivery_in_progress", "concluded"];
+static PACKAGE_STATUSES: [&str; 11] = [
+    "created", "ready_to_ship", "canceled", "shipped", "lost", "stolen", "seized_for_inspection",
+    "returning_to_sender", "returned_to_sender", "awaiting_pickup_by_receiver", "delivered",
+];
+
+/// Adjusts the totals by the order entries a statement moved into or out of
+/// the open statuses. `changes_sql` defines the CTEs `before` and `after`
+/// holding the entries as they were and as they are now; it runs after the
+/// statement, so seller.order_entries is already in its new state.
+fn adjust_seller_totals_sql(changes_sql: &str) -> String {
+    format!(r#"
+        WITH {changes_sql},
+        changes AS (
+            SELECT seller_id, customer_id, order_id, -1 AS sign,
+                   total_amount, freight_value, total_items - total_amount AS total_incentive, total_invoice, total_items
+            FROM before WHERE order_status IN {OPEN_STATUSES_SQL}
+            UNION ALL
+            SELECT seller_id, customer_id, order_id, 1 AS sign,
+                   total_amount, freight_value, total_items - total_amount, total_invoice, total_items
+            FROM after WHERE order_status IN {OPEN_STATUSES_SQL}
+        ), per_order AS (
+            SELECT seller_id, customer_id, order_id,
+                   sum(sign) AS items,
+                   sum(sign * total_amount::FLOAT8) AS total_amount,
+                   sum(sign * freight_value::FLOAT8) AS total_freight,
+                   sum(sign * total_incentive::FLOAT8) AS total_incentive,
+                   sum(sign * total_invoice::FLOAT8) AS total_invoice,
+                   sum(sign * total_items::FLOAT8) AS total_items
+            FROM changes
+            GROUP BY seller_id, customer_id, order_id
+        ), counted AS (
+            -- an order counts while any of its entries is open; before the statement
+            -- it had its open entries now minus the ones the statement opened
+            SELECT o.*, (
+                SELECT count(*) FROM seller.order_entries e
+                WHERE e.seller_id = o.seller_id AND e.customer_id = o.customer_id AND e.order_id = o.order_id
+                  AND e.order_status IN {OPEN_STATUSES_SQL}
+            ) AS open_now
+            FROM per_order o
+        )
+        INSERT INTO seller.seller_totals AS t
+            (seller_id, count_orders, count_items, total_amount, total_freight, total_incentive, total_invoice, total_items, updated_at)
+        SELECT seller_id, sum((open_now > 0)::INT - (open_now - items > 0)::INT), sum(items), sum(total_amount),
+               sum(total_freight), sum(total_incentive), sum(total_invoice), sum(total_items), now()
+        FROM counted
+        GROUP BY seller_id
+        ON CONFLICT (seller_id) DO UPDATE SET
+            count_orders = t.count_orders + EXCLUDED.count_orders,
+            count_items = t.count_items + EXCLUDED.count_items,
+            total_amount = t.total_amount + EXCLUDED.total_amount,
+            total_freight = t.total_freight + EXCLUDED.total_freight,
+            total_incentive = t.total_incentive + EXCLUDED.total_incentive,
+            total_invoice = t.total_invoice + EXCLUDED.total_invoice,
+            total_items = t.total_items + EXCLUDED.total_items,
+            updated_at = EXCLUDED.updated_at
+    "#)
+}
+
+// Statement triggers keeping seller_totals in step with seller.order_entries,
+// whether SellerMS or this extension writes them: the event, its transition
+// tables and the `before`/`after` CTEs built from them
+static SELLER_TOTALS_TRIGGERS: [(&str, &str, &str); 3] = [
+    ("INSERT", "NEW TABLE AS new_entries",
+     "before AS (SELECT * FROM seller.order_entries WHERE false), after AS (SELECT * FROM new_entries)"),
+    ("UPDATE", "OLD TABLE AS old_entries NEW TABLE AS new_entries",
+     "before AS (SELECT * FROM old_entries), after AS (SELECT * FROM new_entries)"),
+    ("DELETE", "OLD TABLE AS old_entries",
+     "before AS (SELECT * FROM old_entries), after AS (SELECT * FROM seller.order_entries WHERE false)"),
+];
+
+/// Creates the per-seller totals, fills them from the current order entries
+/// and installs the triggers that maintain them from then on. Requires
+/// SellerMS' seller.order_entries and, for events applied here, ProductMS'
+/// product.products, where the entries' categories come from.
+#[pg_extern]
+fn setup_seller_dashboard() -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS seller.seller_totals (
+            seller_id INT PRIMARY KEY,
+            count_orders BIGINT NOT NULL DEFAULT 0,
+            count_items BIGINT NOT NULL DEFAULT 0,
+            total_amount FLOAT8 NOT NULL DEFAULT 0,
+            total_freight FLOAT8 NOT NULL DEFAULT 0,
+            total_incentive FLOAT8 NOT NULL DEFAULT 0,
+            total_invoice FLOAT8 NOT NULL DEFAULT 0,
+            total_items FLOAT8 NOT NULL DEFAULT 0,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+    "#)?;
+
+    // the lock holds off writers until the rebuilt totals are maintained by the triggers
+    let rebuild_sql = format!(r#"
+        LOCK TABLE seller.order_entries IN SHARE ROW EXCLUSIVE MODE;
+        TRUNCATE seller.seller_totals;
+        INSERT INTO seller.seller_totals
+            (seller_id, count_orders, count_items, total_amount, total_freight, total_incentive, total_invoice, total_items)
+        SELECT seller_id, count(DISTINCT natural_key), count(product_id), sum(total_amount), sum(freight_value),
+               sum(total_items - total_amount), sum(total_invoice), sum(total_items)
+        FROM seller.order_entries
+        WHERE order_status IN {OPEN_STATUSES_SQL}
+        GROUP BY seller_id;
+    "#);
+    Spi::run(&rebuild_sql)?;
+
+    for (event, referencing, changes_sql) in SELLER_TOTALS_TRIGGERS {
+        let name = format!("seller_totals_on_{}", event.to_lowercase());
+        Spi::run(&format!(r#"
+            CREATE OR REPLACE FUNCTION {name}()
+            RETURNS TRIGGER AS
+            $$
+            BEGIN
+            {adjust_sql};
+            RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DROP TRIGGER IF EXISTS {name} ON seller.order_entries;
+            CREATE TRIGGER {name}
+            AFTER {event} ON seller.order_entries
+            REFERENCING {referencing}
+            FOR EACH STATEMENT
+            EXECUTE FUNCTION {name}();
+        "#, adjust_sql = adjust_seller_totals_sql(changes_sql)))?;
+    }
+    Ok(())
+}
+
+/// Moves all entries of an order to `order_status`, setting the extra
+/// columns in `set_sql`, which may refer to `event_date` as $4.
+fn update_order_entries(
+    customer_id: i32, order_id: i32, order_status: &str, set_sql: &str, event_date: &str,
+) -> Result<(), spi::Error> {
+    Spi::run_with_args(
+        &format!(r#"
+            UPDATE seller.order_entries SET order_status = $3{set_sql}
+            WHERE customer_id = $1 AND order_id = $2;
+        "#),
+        &[customer_id.into(), order_id.into(), order_status.into(), event_date.into()],
+    )
+}
+
+fn apply_order_event(channel: &str, payload: &str) -> Result<(), spi::Error> {
+    let event: Value = match serde_json::from_str(payload) {
+        Ok(event) => event,
+        Err(e) => {
+            log!("seller_ext: malformed `{channel}` payload: {e}");
+            return Ok(());
+        }
+    };
+
+    match channel {
+        "invoice_issued" => {
+            let customer_id = event["customer"]["CustomerId"].as_i64().unwrap_or_default() as i32;
+            let order_id = event["orderId"].as_i64().unwrap_or_default() as i32;
+            // InvoiceIssued items carry no category; it is taken from ProductMS' products
+            let insert_sql = r#"
+                INSERT INTO seller.order_entries (
+                    customer_id, order_id, seller_id, product_id, natural_key, product_name, product_category,
+                    unit_price, quantity, total_items, total_amount, total_incentive, total_invoice,
+                    freight_value, order_status
+                )
+                SELECT $1, $2, i.seller_id, i.product_id, $1::TEXT || '_' || $2::TEXT, i.product_name,
+                       coalesce(p.category, ''),
+                       i.unit_price, i.quantity, i.total_items, i.total_amount, i.total_incentive,
+                       i.total_amount + i.freight_value, i.freight_value, 'INVOICED'
+                FROM jsonb_to_recordset($3) AS i(
+                    seller_id INT, product_id INT, product_name TEXT, unit_price REAL, quantity INT,
+                    total_items REAL, total_amount REAL, total_incentive REAL, freight_value REAL
+                )
+                LEFT JOIN product.products p ON p.seller_id = i.seller_id AND p.product_id = i.product_id
+                ON CONFLICT DO NOTHING;
+            "#;
+            let items = JsonB(event["items"].clone());
+            Spi::run_with_args(insert_sql, &[customer_id.into(), order_id.into(), items.into()])
+        }
+        "shipment" => {
+            let customer_id = event["customerId"].as_i64().unwrap_or_default() as i32;
+            let order_id = event["orderId"].as_i64().unwrap_or_default() as i32;
+            let status = match &event["status"] {
+                Value::Number(ordinal) => SHIPMENT_STATUSES.get(ordinal.as_u64().unwrap_or(0) as usize).copied().unwrap_or_default(),
+                Value::String(status) => status.as_str(),
+                _ => "",
+            };
+            let event_date = event["eventDate"].as_str().unwrap_or_default();
+            match status {
+                "approved" => update_order_entries(
+                    customer_id, order_id, "READY_FOR_SHIPMENT",
+                    ", shipment_date = $4::TIMESTAMPTZ, delivery_status = 'ready_to_ship'", event_date,
+                ),
+                "delivery_in_progress" => update_order_entries(
+                    customer_id, order_id, "IN_TRANSIT", ", delivery_status = 'shipped'", event_date,
+                ),
+                "concluded" => update_order_entries(customer_id, order_id, "DELIVERED", "", event_date),
+                _ => Ok(()),
+            }
+        }
+        "payment_failed" => {
+            let customer_id = event["customer"]["CustomerId"].as_i64().unwrap_or_default() as i32;
+            let order_id = event["orderId"].as_i64().unwrap_or_default() as i32;
+            update_order_entries(customer_id, order_id, "PAYMENT_FAILED", "", "")
+        }
+        "delivery" => {
+            // delivery moves a package, not the order, so the totals stay as they are
+            let delivery_status = match &event["status"] {
+                Value::Number(ordinal) => PACKAGE_STATUSES.get(ordinal.as_u64().unwrap_or(0) as usize).copied().unwrap_or_default(),
+                Value::String(status) => status.as_str(),
+                _ => "",
+            };
+            Spi::run_with_args(
+                r#"
+                UPDATE seller.order_entries
+                SET package_id = $5, delivery_date = $6::TIMESTAMPTZ, delivery_status = $7
+                WHERE customer_id = $1 AND order_id = $2 AND seller_id = $3 AND product_id = $4;
+                "#,
+                &[
+                    (event["customerId"].as_i64().unwrap_or_default() as i32).into(),
+                    (event["orderId"].as_i64().unwrap_or_default() as i32).into(),
+                    (event["sellerId"].as_i64().unwrap_or_default() as i32).into(),
+                    (event["productId"].as_i64().unwrap_or_default() as i32).into(),
+                    (event["packageId"].as_i64().unwrap_or_default() as i32).into(),
+                    event["deliveryDate"].as_str().unwrap_or_default().into(),
+                    delivery_status.into(),
+                ],
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The QUERY_DASHBOARD transaction: the seller's totals and open order
+/// entries, shaped as SellerMS' SellerDashboard. Both come from one statement
+/// and therefore one snapshot. Requires `setup_seller_dashboard()`.
+#[pg_extern]
+fn seller_dashboard(seller_id: i32) -> Result<JsonB, spi::Error> {
+    let dashboard_sql = format!(r#"
+        SELECT jsonb_build_object(
+            'sellerView', coalesce(
+                (SELECT to_jsonb(t) - 'updated_at' FROM seller.seller_totals t WHERE t.seller_id = $1),
+                jsonb_build_object(
+                    'seller_id', $1, 'count_orders', 0, 'count_items', 0, 'total_amount', 0, 'total_freight', 0,
+                    'total_incentive', 0, 'total_invoice', 0, 'total_items', 0
+                )
+            ),
+            'orderEntries', coalesce(
+                (SELECT jsonb_agg(to_jsonb(e) ORDER BY e.customer_id, e.order_id, e.product_id)
+                 FROM seller.order_entries e
+                 WHERE e.seller_id = $1 AND e.order_status IN {OPEN_STATUSES_SQL}),
+                '[]'::JSONB
+            )
+        );
+    "#);
+    let dashboard = Spi::get_one_with_args::<JsonB>(&dashboard_sql, &[seller_id.into()])?;
+    Ok(dashboard.unwrap_or(JsonB(Value::Null)))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+    use serde_json::{json, Value};
+
+    fn setup_seller_tables() -> Result<(), spi::Error> {
+        Spi::run(r#"
+            CREATE SCHEMA seller;
+            CREATE TABLE seller.order_entries (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                seller_id INT NOT NULL,
+                product_id INT NOT NULL,
+                natural_key TEXT NOT NULL,
+                package_id INT,
+                product_name TEXT NOT NULL,
+                product_category TEXT NOT NULL,
+                unit_price REAL NOT NULL,
+                quantity INT NOT NULL,
+                total_items REAL NOT NULL,
+                total_amount REAL NOT NULL,
+                total_incentive REAL NOT NULL,
+                total_invoice REAL NOT NULL,
+                freight_value REAL NOT NULL,
+                shipment_date TIMESTAMPTZ,
+                delivery_date TIMESTAMPTZ,
+                order_status TEXT NOT NULL,
+                delivery_status TEXT,
+                PRIMARY KEY (customer_id, order_id, seller_id, product_id)
+            );
+            CREATE SCHEMA product;
+            CREATE TABLE product.products (seller_id INT, product_id INT, category TEXT);
+            INSERT INTO product.products VALUES (1, 1, 'toys'), (1, 2, 'books');
+        "#)?;
+        crate::setup_seller_dashboard()
+    }
+
+    fn invoice(customer_id: i32, items: &[(i32, i32)]) -> Result<(), spi::Error> {
+        let items: Vec<Value> = items.iter()
+            .map(|(seller_id, product_id)| json!({
+                "seller_id": seller_id, "product_id": product_id, "product_name": "p", "unit_price": 10.0,
+                "quantity": 1, "total_items": 10.0, "total_amount": 8.0, "total_incentive": 2.0, "freight_value": 1.0,
+            }))
+            .collect();
+        let event = json!({ "customer": { "CustomerId": customer_id }, "orderId": 1, "items": items });
+        crate::apply_order_event("invoice_issued", &event.to_string())
+    }
+
+    fn totals(seller_id: i32) -> Result<(Option<i64>, Option<i64>, Option<f64>), spi::Error> {
+        Spi::get_three_with_args(
+            "SELECT count_orders, count_items, total_invoice FROM seller.seller_totals WHERE seller_id = $1",
+            &[seller_id.into()],
+        )
+    }
+
+    #[pg_test]
+    fn totals_follow_entries_into_and_out_of_open_statuses() -> Result<(), spi::Error> {
+        setup_seller_tables()?;
+        invoice(1, &[(1, 1), (1, 2), (2, 3)])?;
+        invoice(2, &[(1, 1)])?;
+        assert_eq!(totals(1)?, (Some(2), Some(3), Some(27.0)));
+        assert_eq!(totals(2)?, (Some(1), Some(1), Some(9.0)));
+
+        let category = Spi::get_one::<String>(
+            "SELECT product_category FROM seller.order_entries WHERE customer_id = 1 AND product_id = 2",
+        )?;
+        assert_eq!(category.as_deref(), Some("books"));
+
+        // shipment keeps the order open, delivery and failed payment close it
+        let shipment = |status: i32| json!({ "customerId": 1, "orderId": 1, "status": status, "eventDate": "2026-01-01T00:00:00Z" });
+        crate::apply_order_event("shipment", &shipment(0).to_string())?;
+        assert_eq!(totals(1)?, (Some(2), Some(3), Some(27.0)));
+        crate::apply_order_event("shipment", &shipment(2).to_string())?;
+        assert_eq!(totals(1)?, (Some(1), Some(1), Some(9.0)));
+        assert_eq!(totals(2)?, (Some(0), Some(0), Some(0.0)));
+
+        let failed = json!({ "customer": { "CustomerId": 2 }, "orderId": 1 });
+        crate::apply_order_event("payment_failed", &failed.to_string())?;
+        assert_eq!(totals(1)?, (Some(0), Some(0), Some(0.0)));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn totals_follow_entries_written_by_seller_ms() -> Result<(), spi::Error> {
+        setup_seller_tables()?;
+        Spi::run(r#"
+            INSERT INTO seller.order_entries (
+                customer_id, order_id, seller_id, product_id, natural_key, product_name, product_category,
+                unit_price, quantity, total_items, total_amount, total_incentive, total_invoice, freight_value, order_status
+            )
+            VALUES (1, 1, 1, 1, '1_1', 'p', '', 10, 1, 10, 10, 0, 11, 1, 'INVOICED'),
+                   (1, 1, 1, 2, '1_1', 'p', '', 10, 2, 20, 20, 0, 21, 1, 'INVOICED');
+        "#)?;
+        assert_eq!(totals(1)?, (Some(1), Some(2), Some(32.0)));
+
+        Spi::run("DELETE FROM seller.order_entries WHERE product_id = 2")?;
+        assert_eq!(totals(1)?, (Some(1), Some(1), Some(11.0)));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn dashboard_shows_totals_and_open_entries() -> Result<(), spi::Error> {
+        setup_seller_tables()?;
+        invoice(1, &[(1, 1)])?;
+        invoice(2, &[(1, 2)])?;
+        let shipment = json!({ "customerId": 2, "orderId": 1, "status": 2, "eventDate": "2026-01-01T00:00:00Z" });
+        crate::apply_order_event("shipment", &shipment.to_string())?;
+
+        let dashboard = crate::seller_dashboard(1)?.0;
+        assert_eq!(dashboard["sellerView"]["count_orders"], 1);
+        let entries = dashboard["orderEntries"].as_array().cloned().unwrap_or_default();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["customer_id"], 1);
+
+        let empty = crate::seller_dashboard(9)?.0;
+        assert_eq!(empty["sellerView"]["count_orders"], 0);
+        assert_eq!(empty["orderEntries"], json!([]));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
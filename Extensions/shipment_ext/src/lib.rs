@@ -1,10 +1,12 @@
 use pgrx::{
-    bgworkers::BackgroundWorkerBuilder, log, pg_extern, pg_guard,
+    bgworkers::BackgroundWorkerBuilder, datum::TimestampWithTimeZone, default, error, log, pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
+use marks::notify;
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde_json::{json, Value};
 use std::time::Duration;
 
 // Export PostgreSQL extension
@@ -29,6 +31,8 @@ const BGW_ID_OFFSET: i32 = 18;  // Ensure unique BGW ID
 
 #[pg_extern]
 fn setup_shipment() -> Result<(), spi::Error> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     let create_table_sql = r#"
         CREATE TABLE IF NOT EXISTS CHECKOUT (
             stream_id TEXT NOT NULL,
@@ -36,11 +40,37 @@ fn setup_shipment() -> Result<(), spi::Error> {
             transaction_type TEXT NOT NULL,
             mark_status TEXT NOT NULL,
             customer_id TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#;
 
     Spi::run(create_table_sql)?;
+    marks::ensure_mark_columns("CHECKOUT")?;
+
+    let create_delivery_sql = r#"
+        CREATE TABLE IF NOT EXISTS DELIVERYUPDATE (
+            stream_id TEXT NOT NULL,
+            instance_id TEXT NOT NULL,
+            transaction_type TEXT NOT NULL,
+            mark_status TEXT NOT NULL,
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
+        );
+    "#;
+
+    Spi::run(create_delivery_sql)?;
+    marks::ensure_mark_columns("DELIVERYUPDATE")?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
+
+    Spi::run(freight::SETUP_SQL)?;
     Ok(())
 }
 
@@ -51,20 +81,63 @@ fn setup_shipment() -> Result<(), spi::Error> {
 #[pg_extern]
 fn shipment_add_checkout_transaction_mark(
     stream_id: &str, instance_id: &str, transaction_type: &str,
-    customer_id: &str, mark_status: &str, db: &str
+    customer_id: &str, mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
+) -> Result<(), spi::Error> {
+    let insert_sql = r#"
+        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
+    "#;
+
+    Spi::run_with_args(insert_sql, &[
+        stream_id.into(), instance_id.into(), transaction_type.into(),
+        customer_id.into(), mark_status.into(), db.into(), submitted_at.into(),
+    ])?;
+    Ok(())
+}
+
+#[pg_extern]
+fn shipment_add_delivery_transaction_mark(
+    stream_id: &str, instance_id: &str, transaction_type: &str,
+    mark_status: &str, db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), spi::Error> {
     let insert_sql = r#"
-        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db)
+        INSERT INTO DELIVERYUPDATE (stream_id, instance_id, transaction_type, mark_status, db, submitted_at)
         VALUES ($1, $2, $3, $4, $5, $6);
     "#;
 
     Spi::run_with_args(insert_sql, &[
         stream_id.into(), instance_id.into(), transaction_type.into(),
-        customer_id.into(), mark_status.into(), db.into(),
+        mark_status.into(), db.into(), submitted_at.into(),
     ])?;
     Ok(())
 }
 
+static MARK_TABLES: [&str; 2] = ["CHECKOUT", "DELIVERYUPDATE"];
+
+#[pg_extern]
+fn shipment_purge_marks(
+    before: TimestampWithTimeZone, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("shipment"), Some(before), None, archive)?;
+    }
+    Ok(purged)
+}
+
+#[pg_extern]
+fn shipment_purge_run_marks(
+    run_id: i64, archive: default!(bool, false)
+) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("shipment"), None, Some(run_id), archive)?;
+    }
+    Ok(purged)
+}
+
 ////////////////////////////////////////
 // 4. Start BGWs
 ////////////////////////////////////////
@@ -180,3 +253,435 @@ fn run_bgworker(id: i32) {
         }
     }
 }
+
+////////////////////////////////////////
+// 7. Shipment Creation
+////////////////////////////////////////
+
+const CHECKOUT_STREAM_ID: &str = "TransactionMark_CUSTOMER_SESSION";
+
+// ShipmentStatus and PackageStatus ordinals, as serialized by the C# services
+const SHIPMENT_APPROVED: i32 = 0;
+const SHIPMENT_DELIVERY_IN_PROGRESS: i32 = 1;
+const SHIPMENT_CONCLUDED: i32 = 2;
+const PACKAGE_DELIVERED: i32 = 10;
+
+/// Creates the shipment of a PaymentConfirmed event and its packages, as
+/// ShipmentService does, then publishes the approved ShipmentNotification and
+/// the SUCCESS checkout mark. A package holds one order item; package ids are
+/// numbered from 1 by seller and product, so each seller's packages are
+/// contiguous. Packages keep the freight_value of their order item, which is
+/// what cart_ext quoted at checkout and the customer paid, so they are not
+/// quoted again (rates may have changed since; see `shipment_freight_quote`).
+/// Everything commits, or is published, together with the caller's
+/// transaction. Returns the published ShipmentNotification.
+#[pg_extern]
+fn shipment_create(payment_confirmed: JsonB) -> Result<JsonB, spi::Error> {
+    let payment_confirmed = payment_confirmed.0;
+    let customer = &payment_confirmed["customer"];
+    let instance_id = payment_confirmed["instanceId"].as_str().unwrap_or_default();
+    let (Some(customer_id), Some(order_id)) = (customer["CustomerId"].as_i64(), payment_confirmed["orderId"].as_i64()) else {
+        error!("shipment_ext: PaymentConfirmed payload has no customer or order id");
+    };
+    let (customer_id, order_id) = (customer_id as i32, order_id as i32);
+
+    let mut items = payment_confirmed["items"].as_array().cloned().unwrap_or_default();
+    items.sort_by_key(|item| (item["seller_id"].as_i64(), item["product_id"].as_i64()));
+    let total_freight_value: f64 = items.iter().map(|item| item["freight_value"].as_f64().unwrap_or_default()).sum();
+
+    let insert_shipment_sql = r#"
+        INSERT INTO shipment.shipments (
+            customer_id, order_id, package_count, total_freight_value, request_date, status,
+            first_name, last_name, street, complement, zip_code, city, state
+        )
+        VALUES ($1, $2, $3, $4::REAL, now(), 'approved', $5, $6, $7, $8, $9, $10, $11);
+    "#;
+    let customer_field = |field: &str| customer[field].as_str().unwrap_or_default().to_string();
+    Spi::run_with_args(insert_shipment_sql, &[
+        customer_id.into(), order_id.into(), (items.len() as i32).into(), total_freight_value.into(),
+        customer_field("FirstName").into(), customer_field("LastName").into(),
+        customer_field("Street").into(), customer_field("Complement").into(),
+        customer_field("ZipCode").into(), customer_field("City").into(), customer_field("State").into(),
+    ])?;
+
+    let insert_package_sql = r#"
+        INSERT INTO shipment.packages (
+            customer_id, order_id, package_id, seller_id, product_id, product_name,
+            freight_value, shipping_date, quantity, status
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7::REAL, now(), $8, 'shipped');
+    "#;
+    for (i, item) in items.iter().enumerate() {
+        Spi::run_with_args(insert_package_sql, &[
+            customer_id.into(), order_id.into(), (i as i32 + 1).into(),
+            (item["seller_id"].as_i64().unwrap_or_default() as i32).into(),
+            (item["product_id"].as_i64().unwrap_or_default() as i32).into(),
+            item["product_name"].as_str().unwrap_or_default().into(),
+            item["freight_value"].as_f64().unwrap_or_default().into(),
+            (item["quantity"].as_i64().unwrap_or_default() as i32).into(),
+        ])?;
+    }
+
+    let event_date = Spi::get_one::<JsonB>("SELECT to_jsonb(now())")?.map(|date| date.0).unwrap_or(Value::Null);
+    let notification = json!({
+        "customerId": customer_id,
+        "orderId": order_id,
+        "eventDate": event_date,
+        "instanceId": instance_id,
+        "status": SHIPMENT_APPROVED,
+    });
+    notify("shipment", &notification)?;
+
+    shipment_add_checkout_transaction_mark(
+        CHECKOUT_STREAM_ID, instance_id, "CUSTOMER_SESSION",
+        &customer_id.to_string(), "SUCCESS", "shipment", None,
+    )?;
+    Ok(JsonB(notification))
+}
+
+////////////////////////////////////////
+// 8. Delivery Update
+////////////////////////////////////////
+
+const DELIVERY_STREAM_ID: &str = "TransactionMark_UPDATE_DELIVERY";
+
+fn notify_shipment_status(
+    customer_id: i32, order_id: i32, event_date: &Value, instance_id: &str, status: i32,
+) -> Result<(), spi::Error> {
+    notify("shipment", &json!({
+        "customerId": customer_id,
+        "orderId": order_id,
+        "eventDate": event_date,
+        "instanceId": instance_id,
+        "status": status,
+    }))
+}
+
+/// Delivers the oldest shipped order of each of the first `max_sellers`
+/// sellers, as ShipmentService.UpdateShipment does: the seller's packages of
+/// that order become delivered, the shipment moves to delivery_in_progress and
+/// then concluded once all its packages are delivered, and a `delivery` event
+/// is published per package, followed by the UPDATE_DELIVERY mark.
+///
+/// Every writer locks the shipment row before its packages, and a shipment
+/// locked by a concurrent call is skipped rather than waited for, so
+/// concurrent calls deliver disjoint orders. `instance_id` identifies the
+/// driver's transaction in the events and the mark. Returns the number of
+/// packages delivered.
+#[pg_extern]
+fn shipment_update_delivery(max_sellers: i32, instance_id: &str) -> Result<i64, spi::Error> {
+    if instance_id.is_empty() {
+        error!("shipment_ext: shipment_update_delivery requires an instance_id");
+    }
+
+    let oldest_sql = r#"
+        SELECT seller_id, customer_id, order_id FROM (
+            SELECT DISTINCT ON (seller_id) seller_id, customer_id, order_id
+            FROM shipment.packages
+            WHERE status = 'shipped'
+            ORDER BY seller_id, shipping_date, customer_id, order_id
+        ) oldest
+        ORDER BY seller_id
+        LIMIT $1;
+    "#;
+    let oldest: Vec<(i32, i32, i32)> = Spi::connect(|client| {
+        client.select(oldest_sql, None, &[max_sellers.into()])?
+            .map(|row| Ok((
+                row.get_by_name::<i32, _>("seller_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("customer_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("order_id")?.unwrap_or_default(),
+            )))
+            .collect::<Result<_, spi::Error>>()
+    })?;
+
+    let event_date = Spi::get_one::<JsonB>("SELECT to_jsonb(now())")?.map(|date| date.0).unwrap_or(Value::Null);
+    let mut delivered = 0;
+    for (seller_id, customer_id, order_id) in oldest {
+        let shipment = Spi::get_two_with_args::<String, i32>(
+            r#"
+            SELECT status, package_count FROM shipment.shipments
+            WHERE customer_id = $1 AND order_id = $2
+            FOR UPDATE SKIP LOCKED;
+            "#,
+            &[customer_id.into(), order_id.into()],
+        );
+        let (status, package_count) = match shipment {
+            Ok((Some(status), Some(package_count))) => (status, package_count),
+            Err(spi::Error::InvalidPosition) | Ok(_) => continue,
+            Err(e) => return Err(e),
+        };
+
+        // the snapshot that picked this order may be stale; only still-shipped packages move
+        let deliver_sql = r#"
+            UPDATE shipment.packages SET status = 'delivered', delivery_date = now()
+            WHERE customer_id = $1 AND order_id = $2 AND seller_id = $3 AND status = 'shipped'
+            RETURNING package_id, product_id, product_name;
+        "#;
+        let packages: Vec<(i32, i32, String)> = Spi::connect_mut(|client| {
+            client.update(deliver_sql, None, &[customer_id.into(), order_id.into(), seller_id.into()])?
+                .map(|row| Ok((
+                    row.get_by_name::<i32, _>("package_id")?.unwrap_or_default(),
+                    row.get_by_name::<i32, _>("product_id")?.unwrap_or_default(),
+                    row.get_by_name::<String, _>("product_name")?.unwrap_or_default(),
+                )))
+                .collect::<Result<_, spi::Error>>()
+        })?;
+        if packages.is_empty() {
+            continue;
+        }
+
+        if status == "approved" {
+            Spi::run_with_args(
+                "UPDATE shipment.shipments SET status = 'delivery_in_progress' WHERE customer_id = $1 AND order_id = $2",
+                &[customer_id.into(), order_id.into()],
+            )?;
+            notify_shipment_status(customer_id, order_id, &event_date, instance_id, SHIPMENT_DELIVERY_IN_PROGRESS)?;
+        }
+
+        for (package_id, product_id, product_name) in &packages {
+            notify("delivery", &json!({
+                "customerId": customer_id,
+                "orderId": order_id,
+                "packageId": package_id,
+                "sellerId": seller_id,
+                "productId": product_id,
+                "productName": product_name,
+                "status": PACKAGE_DELIVERED,
+                "deliveryDate": event_date,
+                "instanceId": instance_id,
+            }))?;
+        }
+        delivered += packages.len() as i64;
+
+        let delivered_count = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM shipment.packages WHERE customer_id = $1 AND order_id = $2 AND status = 'delivered'",
+            &[customer_id.into(), order_id.into()],
+        )?.unwrap_or_default();
+        if delivered_count == package_count as i64 {
+            Spi::run_with_args(
+                "UPDATE shipment.shipments SET status = 'concluded' WHERE customer_id = $1 AND order_id = $2",
+                &[customer_id.into(), order_id.into()],
+            )?;
+            notify_shipment_status(customer_id, order_id, &event_date, instance_id, SHIPMENT_CONCLUDED)?;
+        }
+    }
+
+    shipment_add_delivery_transaction_mark(DELIVERY_STREAM_ID, instance_id, "UPDATE_DELIVERY", "SUCCESS", "shipment", None)?;
+    Ok(delivered)
+}
+
+////////////////////////////////////////
+// 9. Freight Quote
+////////////////////////////////////////
+
+/// Quotes the freight of order items (as carried by PaymentConfirmed) with the
+/// calculator cart_ext prices checkouts with. Items of sellers without a rate
+/// keep the freight they carry. Returns the items with `freight_value` set.
+#[pg_extern]
+fn shipment_freight_quote(items: JsonB) -> Result<JsonB, spi::Error> {
+    let Value::Array(mut items) = items.0 else {
+        error!("shipment_freight_quote: expected an array of order items");
+    };
+    let lines: Vec<freight::FreightLine> = items.iter()
+        .map(|item| {
+            let quantity = item["quantity"].as_i64().unwrap_or_default() as i32;
+            freight::FreightLine {
+                seller_id: item["seller_id"].as_i64().unwrap_or_default() as i32,
+                product_id: item["product_id"].as_i64().unwrap_or_default() as i32,
+                quantity,
+                amount: item["unit_price"].as_f64().unwrap_or_default() as f32 * quantity as f32,
+            }
+        })
+        .collect();
+
+    let seller_ids: Vec<i32> = lines.iter().map(|line| line.seller_id).collect();
+    let table = Spi::get_one_with_args::<JsonB>(
+        freight::LOAD_SQL,
+        &[seller_ids.into(), freight::DEFAULT_SELLER_ID.into()],
+    )?;
+    let table: freight::FreightTable = match table.map(|table| serde_json::from_value(table.0)) {
+        Some(Ok(table)) => table,
+        Some(Err(e)) => error!("shipment_freight_quote: invalid freight rates: {e}"),
+        None => freight::FreightTable::default(),
+    };
+
+    for (item, freight_value) in items.iter_mut().zip(table.quote(&lines)) {
+        if let Some(freight_value) = freight_value {
+            item["freight_value"] = json!(freight_value);
+        }
+    }
+    Ok(JsonB(Value::Array(items)))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::{self, Spi};
+    use pgrx::JsonB;
+    use serde_json::json;
+
+    // The subset of ShipmentMS' tables shipment creation and delivery touch
+    fn setup_shipment_tables() -> Result<(), spi::Error> {
+        Spi::run(r#"
+            CREATE SCHEMA shipment;
+            CREATE TABLE shipment.shipments (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                package_count INT NOT NULL,
+                total_freight_value REAL NOT NULL,
+                request_date TIMESTAMPTZ NOT NULL,
+                status TEXT NOT NULL,
+                first_name TEXT, last_name TEXT, street TEXT, complement TEXT,
+                zip_code TEXT, city TEXT, state TEXT,
+                PRIMARY KEY (customer_id, order_id)
+            );
+            CREATE TABLE shipment.packages (
+                customer_id INT NOT NULL,
+                order_id INT NOT NULL,
+                package_id INT NOT NULL,
+                seller_id INT NOT NULL,
+                product_id INT NOT NULL,
+                product_name TEXT NOT NULL,
+                freight_value REAL NOT NULL,
+                shipping_date TIMESTAMPTZ NOT NULL,
+                delivery_date TIMESTAMPTZ,
+                quantity INT NOT NULL,
+                status TEXT NOT NULL,
+                PRIMARY KEY (customer_id, order_id, package_id)
+            );
+        "#)?;
+        crate::setup_shipment()
+    }
+
+    #[pg_test]
+    fn packages_are_numbered_by_seller_and_product_and_keep_item_freight() -> Result<(), spi::Error> {
+        setup_shipment_tables()?;
+        let item = |seller_id: i32, product_id: i32, freight_value: f64| json!({
+            "seller_id": seller_id, "product_id": product_id, "product_name": "p",
+            "freight_value": freight_value, "quantity": 1, "unit_price": 10.0,
+        });
+        crate::shipment_create(JsonB(json!({
+            "customer": { "CustomerId": 1 },
+            "orderId": 1,
+            "items": [item(2, 1, 1.5), item(1, 5, 2.5), item(1, 2, 4.0)],
+            "instanceId": "i-1",
+        })))?;
+
+        let packages = Spi::get_one::<Vec<String>>(r#"
+            SELECT array_agg(format('%s:%s/%s:%s', package_id, seller_id, product_id, freight_value) ORDER BY package_id)
+            FROM shipment.packages
+        "#)?;
+        assert_eq!(packages, Some(vec!["1:1/2:4".to_string(), "2:1/5:2.5".to_string(), "3:2/1:1.5".to_string()]));
+
+        let (package_count, total_freight) = Spi::get_two::<i32, f32>(
+            "SELECT package_count, total_freight_value FROM shipment.shipments",
+        )?;
+        assert_eq!(package_count, Some(3));
+        assert!((total_freight.unwrap_or_default() - 8.0).abs() < 1e-6);
+
+        let marks = Spi::get_one::<i64>("SELECT count(*) FROM CHECKOUT WHERE instance_id = 'i-1' AND mark_status = 'SUCCESS'")?;
+        assert_eq!(marks, Some(1));
+        Ok(())
+    }
+
+    fn ship(customer_id: i32, order_id: i32, sellers: &[i32], days_ago: i32) -> Result<(), spi::Error> {
+        Spi::run_with_args(
+            r#"
+            INSERT INTO shipment.shipments (customer_id, order_id, package_count, total_freight_value, request_date, status)
+            VALUES ($1, $2, $3, 0, now(), 'approved');
+            "#,
+            &[customer_id.into(), order_id.into(), (sellers.len() as i32).into()],
+        )?;
+        Spi::run_with_args(
+            r#"
+            INSERT INTO shipment.packages
+                (customer_id, order_id, package_id, seller_id, product_id, product_name, freight_value, shipping_date, quantity, status)
+            SELECT $1, $2, n::INT, seller_id, 1, 'p', 0, now() - make_interval(days => $4), 1, 'shipped'
+            FROM unnest($3::INT[]) WITH ORDINALITY AS s (seller_id, n);
+            "#,
+            &[customer_id.into(), order_id.into(), sellers.to_vec().into(), days_ago.into()],
+        )
+    }
+
+    fn package_statuses() -> Result<Option<Vec<String>>, spi::Error> {
+        Spi::get_one(r#"
+            SELECT array_agg(format('%s-%s/%s:%s', customer_id, order_id, seller_id, status)
+                             ORDER BY customer_id, order_id, seller_id)
+            FROM shipment.packages
+        "#)
+    }
+
+    fn shipment_status(customer_id: i32) -> Result<Option<String>, spi::Error> {
+        Spi::get_one_with_args("SELECT status FROM shipment.shipments WHERE customer_id = $1", &[customer_id.into()])
+    }
+
+    #[pg_test]
+    fn delivery_picks_oldest_order_of_first_sellers() -> Result<(), spi::Error> {
+        setup_shipment_tables()?;
+        ship(1, 1, &[1], 1)?;
+        ship(2, 1, &[1], 2)?;
+        ship(3, 1, &[2], 1)?;
+        ship(4, 1, &[3], 5)?;
+
+        assert_eq!(crate::shipment_update_delivery(2, "d-1")?, 2);
+        assert_eq!(package_statuses()?, Some(vec![
+            "1-1/1:shipped".to_string(),
+            "2-1/1:delivered".to_string(),
+            "3-1/2:delivered".to_string(),
+            "4-1/3:shipped".to_string(),
+        ]));
+        let marks = Spi::get_one::<i64>("SELECT count(*) FROM DELIVERYUPDATE WHERE instance_id = 'd-1'")?;
+        assert_eq!(marks, Some(1));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn shipment_concludes_once_every_seller_delivered() -> Result<(), spi::Error> {
+        setup_shipment_tables()?;
+        ship(1, 1, &[1, 2], 1)?;
+
+        assert_eq!(crate::shipment_update_delivery(1, "d-1")?, 1);
+        assert_eq!(shipment_status(1)?.as_deref(), Some("delivery_in_progress"));
+
+        assert_eq!(crate::shipment_update_delivery(1, "d-2")?, 1);
+        assert_eq!(shipment_status(1)?.as_deref(), Some("concluded"));
+        assert_eq!(crate::shipment_update_delivery(1, "d-3")?, 0);
+        Ok(())
+    }
+
+    #[pg_test]
+    fn delivery_skips_packages_without_shipment() -> Result<(), spi::Error> {
+        setup_shipment_tables()?;
+        ship(1, 1, &[1], 2)?;
+        ship(2, 1, &[1], 1)?;
+        // an order whose shipment row cannot be taken, missing or locked by a
+        // concurrent call, is left for a later call
+        Spi::run("DELETE FROM shipment.shipments WHERE customer_id = 1")?;
+
+        assert_eq!(crate::shipment_update_delivery(1, "d-1")?, 0);
+        assert_eq!(package_statuses()?, Some(vec!["1-1/1:shipped".to_string(), "2-1/1:shipped".to_string()]));
+        Ok(())
+    }
+
+    #[pg_test(error = "shipment_ext: shipment_update_delivery requires an instance_id")]
+    fn delivery_requires_instance_id() -> Result<(), spi::Error> {
+        setup_shipment_tables()?;
+        crate::shipment_update_delivery(1, "").map(|_| ())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
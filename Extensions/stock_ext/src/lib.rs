@@ -1,11 +1,17 @@
 use pgrx::{
     bgworkers::BackgroundWorkerBuilder,
-    log, pg_extern, pg_guard,
+    datum::{TimestampWithTimeZone, TryFromDatumError},
+    default, error, log, pg_extern, pg_guard,
     pg_sys::{self, panic::register_pg_guard_panic_hook},
     spi::{self, Spi, SpiError},
-    FromDatum, IntoDatum,
+    FromDatum, IntoDatum, JsonB,
 };
+use pgrx::prelude::{name, pg_trigger, PgHeapTuple, PgTrigger, TableIterator, WhoAllocated};
+use marks::{notify, CartItem};
 use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
 use std::time::Duration;
 
 pgrx::pg_module_magic!();
@@ -16,6 +22,8 @@ pgrx::pg_module_magic!();
 
 #[pg_extern]
 fn setup_stock() -> Result<(), SpiError> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+
     Spi::run(r#"
         CREATE TABLE IF NOT EXISTS CHECKOUT (
             stream_id TEXT NOT NULL,
@@ -23,9 +31,14 @@ fn setup_stock() -> Result<(), SpiError> {
             transaction_type TEXT NOT NULL,
             mark_status TEXT NOT NULL,
             customer_id TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#)?;
+    marks::ensure_mark_columns("CHECKOUT")?;
 
     Spi::run(r#"
         CREATE TABLE IF NOT EXISTS PRODUCTUPDATE (
@@ -34,9 +47,30 @@ fn setup_stock() -> Result<(), SpiError> {
             transaction_type TEXT NOT NULL,
             seller_id TEXT NOT NULL,
             mark_status TEXT NOT NULL,
-            db TEXT NOT NULL
+            db TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            submitted_at TIMESTAMPTZ,
+            run_id BIGINT DEFAULT mark_run_id(),
+            reason TEXT
         );
     "#)?;
+    marks::ensure_mark_columns("PRODUCTUPDATE")?;
+
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS STOCK_RESERVATIONS (
+            instance_id TEXT NOT NULL,
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            quantity INT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'reserved',
+            customer_id INT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (instance_id, seller_id, product_id)
+        );
+    "#)?;
+
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
 
     Ok(())
 }
@@ -49,10 +83,11 @@ fn stock_add_checkout_transaction_mark(
     customer_id: &str,
     mark_status: &str,
     db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), SpiError> {
     let insert_sql = r#"
-        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO CHECKOUT (stream_id, instance_id, transaction_type, customer_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
 
     Spi::run_with_args(
@@ -64,6 +99,7 @@ fn stock_add_checkout_transaction_mark(
             customer_id.into(),
             mark_status.into(),
             db.into(),
+            submitted_at.into(),
         ],
     )?;
 
@@ -78,10 +114,11 @@ fn stock_add_product_transaction_mark(
     seller_id: &str,
     mark_status: &str,
     db: &str,
+    submitted_at: default!(Option<TimestampWithTimeZone>, "NULL"),
 ) -> Result<(), SpiError> {
     let insert_sql = r#"
-        INSERT INTO PRODUCTUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db)
-        VALUES ($1, $2, $3, $4, $5, $6);
+        INSERT INTO PRODUCTUPDATE (stream_id, instance_id, transaction_type, seller_id, mark_status, db, submitted_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
     "#;
 
     Spi::run_with_args(
@@ -93,12 +130,39 @@ fn stock_add_product_transaction_mark(
             seller_id.into(),
             mark_status.into(),
             db.into(),
+            submitted_at.into(),
         ],
     )?;
 
     Ok(())
 }
 
+static MARK_TABLES: [&str; 2] = ["CHECKOUT", "PRODUCTUPDATE"];
+
+#[pg_extern]
+fn stock_purge_marks(
+    before: TimestampWithTimeZone,
+    archive: default!(bool, false),
+) -> Result<i64, SpiError> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("stock"), Some(before), None, archive)?;
+    }
+    Ok(purged)
+}
+
+#[pg_extern]
+fn stock_purge_run_marks(
+    run_id: i64,
+    archive: default!(bool, false),
+) -> Result<i64, SpiError> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, Some("stock"), None, Some(run_id), archive)?;
+    }
+    Ok(purged)
+}
+
 ////////////////////////////////////////
 // 2. Hardcode 5 channels + BGWs
 ////////////////////////////////////////
@@ -228,5 +292,866 @@ fn run_bgworker(id: i32) {
     }
 }
 
+////////////////////////////////////////
+// 4. Stock Reservation Engine
+////////////////////////////////////////
+
+const CHECKOUT_STREAM_ID: &str = "TransactionMark_CUSTOMER_SESSION";
+
+// ItemStatus ordinals, as serialized by the C# services
+const ITEM_UNAVAILABLE: i32 = 0;
+const ITEM_OUT_OF_STOCK: i32 = 1;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReserveStock {
+    #[serde(default)]
+    timestamp: Value,
+    customer_checkout: Value,
+    items: Vec<CartItem>,
+    instance_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProductStatus {
+    id: i32,
+    status: i32,
+    unit_price: f32,
+    old_unit_price: f32,
+    qty_available: i32,
+}
+
+impl ProductStatus {
+    fn new(id: i32, status: i32, qty_available: i32) -> Self {
+        ProductStatus { id, status, unit_price: 0.0, old_unit_price: 0.0, qty_available }
+    }
+}
+
+/// Reserves the items of a ReserveStock event (the `checkout` channel payload).
+///
+/// Rows are locked one at a time in (seller_id, product_id) order, so concurrent
+/// reservations over overlapping items cannot deadlock. Each item is reserved
+/// in full or reported as unavailable; StockConfirmed / ReserveStockFailed and
+/// any failure mark are published in the caller's transaction.
+#[pg_extern]
+fn stock_reserve(
+    items: JsonB,
+    raise_stock_failed: default!(bool, true),
+) -> Result<JsonB, SpiError> {
+    let checkout: ReserveStock = match serde_json::from_value(items.0) {
+        Ok(checkout) => checkout,
+        Err(e) => error!("stock_reserve: invalid ReserveStock payload: {e}"),
+    };
+    let customer_id = checkout.customer_checkout["CustomerId"].as_i64().unwrap_or(0) as i32;
+
+    let mut sorted = checkout.items.clone();
+    sorted.sort_by_key(|item| (item.seller_id, item.product_id));
+
+    let mut found = 0;
+    let mut reserved: Vec<CartItem> = Vec::new();
+    let mut unavailable: Vec<ProductStatus> = Vec::new();
+
+    for item in sorted {
+        let row = Spi::get_three_with_args::<i32, i32, String>(
+            r#"
+            SELECT qty_available, qty_reserved, version FROM stock.stock_items
+            WHERE seller_id = $1 AND product_id = $2
+            FOR UPDATE;
+            "#,
+            &[item.seller_id.into(), item.product_id.into()],
+        );
+        let (qty_available, qty_reserved, version) = match row {
+            Err(SpiError::InvalidPosition) => (None, None, None),
+            other => other?,
+        };
+        let (Some(qty_available), Some(qty_reserved)) = (qty_available, qty_reserved) else {
+            unavailable.push(ProductStatus::new(item.product_id, ITEM_UNAVAILABLE, 0));
+            continue;
+        };
+        found += 1;
+
+        if version.as_deref() != Some(item.version.as_str()) {
+            unavailable.push(ProductStatus::new(item.product_id, ITEM_UNAVAILABLE, 0));
+            continue;
+        }
+        if qty_available < qty_reserved + item.quantity {
+            unavailable.push(ProductStatus::new(item.product_id, ITEM_OUT_OF_STOCK, qty_available));
+            continue;
+        }
+
+        with_movement("reserve", Some(&checkout.instance_id), None, || Spi::run_with_args(
+            r#"
+            UPDATE stock.stock_items SET qty_reserved = qty_reserved + $3, updated_at = now()
+            WHERE seller_id = $1 AND product_id = $2;
+            "#,
+            &[item.seller_id.into(), item.product_id.into(), item.quantity.into()],
+        ))?;
+        Spi::run_with_args(
+            r#"
+            INSERT INTO STOCK_RESERVATIONS (instance_id, seller_id, product_id, quantity, customer_id)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (instance_id, seller_id, product_id) DO UPDATE SET
+                quantity = STOCK_RESERVATIONS.quantity + EXCLUDED.quantity,
+                updated_at = now();
+            "#,
+            &[
+                checkout.instance_id.as_str().into(),
+                item.seller_id.into(),
+                item.product_id.into(),
+                item.quantity.into(),
+                customer_id.into(),
+            ],
+        )?;
+        reserved.push(item);
+    }
+
+    let status = if found == 0 {
+        stock_add_checkout_transaction_mark(
+            CHECKOUT_STREAM_ID,
+            &checkout.instance_id,
+            "CUSTOMER_SESSION",
+            &customer_id.to_string(),
+            "ERROR",
+            "stock",
+            None,
+        )?;
+        "error"
+    } else {
+        if !reserved.is_empty() {
+            notify("stock_confirmed", &json!({
+                "timestamp": checkout.timestamp,
+                "customerCheckout": checkout.customer_checkout,
+                "items": reserved,
+                "instanceId": checkout.instance_id,
+            }))?;
+        }
+        if !unavailable.is_empty() && raise_stock_failed {
+            notify("stock_failed", &json!({
+                "timestamp": checkout.timestamp,
+                "customerCheckout": checkout.customer_checkout,
+                "products": unavailable,
+                "instanceId": checkout.instance_id,
+            }))?;
+        }
+        if reserved.is_empty() {
+            stock_add_checkout_transaction_mark(
+                CHECKOUT_STREAM_ID,
+                &checkout.instance_id,
+                "CUSTOMER_SESSION",
+                &customer_id.to_string(),
+                "NOT_ACCEPTED",
+                "stock",
+                None,
+            )?;
+            "not_accepted"
+        } else if unavailable.is_empty() {
+            "reserved"
+        } else {
+            "partially_reserved"
+        }
+    };
+
+    Ok(JsonB(json!({
+        "instanceId": checkout.instance_id,
+        "status": status,
+        "reserved": reserved,
+        "unavailable": unavailable,
+    })))
+}
+
+/// Applies a payment outcome to every open reservation of `instance_id`,
+/// locking stock rows in the same order as `stock_reserve`.
+fn settle_reservation(instance_id: &str, confirm: bool) -> Result<i64, SpiError> {
+    let status = if confirm { "confirmed" } else { "canceled" };
+    let mut reservations = Spi::connect_mut(|client| {
+        let mut reservations = Vec::new();
+        for row in client.update(
+            r#"
+            UPDATE STOCK_RESERVATIONS SET status = $2, updated_at = now()
+            WHERE instance_id = $1 AND status = 'reserved'
+            RETURNING seller_id, product_id, quantity;
+            "#,
+            None,
+            &[instance_id.into(), status.into()],
+        )? {
+            reservations.push((
+                row.get_by_name::<i32, _>("seller_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("product_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("quantity")?.unwrap_or_default(),
+            ));
+        }
+        Ok::<_, SpiError>(reservations)
+    })?;
+    reservations.sort_unstable();
+
+    let settle_sql = if confirm {
+        r#"
+        UPDATE stock.stock_items SET
+            qty_available = qty_available - $3,
+            qty_reserved = qty_reserved - $3,
+            order_count = order_count + 1,
+            updated_at = now()
+        WHERE seller_id = $1 AND product_id = $2;
+        "#
+    } else {
+        r#"
+        UPDATE stock.stock_items SET qty_reserved = qty_reserved - $3, updated_at = now()
+        WHERE seller_id = $1 AND product_id = $2;
+        "#
+    };
+    let movement = if confirm { "confirm" } else { "release" };
+    with_movement(movement, Some(instance_id), None, || {
+        for &(seller_id, product_id, quantity) in &reservations {
+            Spi::run_with_args(settle_sql, &[seller_id.into(), product_id.into(), quantity.into()])?;
+        }
+        Ok(())
+    })?;
+
+    Ok(reservations.len() as i64)
+}
+
+#[pg_extern]
+fn stock_confirm(instance_id: &str) -> Result<i64, SpiError> {
+    settle_reservation(instance_id, true)
+}
+
+/// Releases the reservations `stock_reserve` tracked for `instance_id`.
+/// Stock reserved by StockMS itself has no STOCK_RESERVATIONS rows, so this
+/// is a no-op for it; StockMS releases that stock in its own PaymentFailed
+/// handler (CancelReservation), and releasing it here as well would count
+/// the release twice.
+#[pg_extern]
+fn stock_cancel(instance_id: &str) -> Result<i64, SpiError> {
+    settle_reservation(instance_id, false)
+}
+
+////////////////////////////////////////
+// 5. Stock Movement Ledger
+////////////////////////////////////////
+
+/// Creates STOCK_LEDGER and the trigger that appends every change of
+/// stock.stock_items to it. Items that have no entry yet get an opening
+/// `adjust` entry with their current stock. Requires the table to exist.
+#[pg_extern]
+fn setup_stock_ledger() -> Result<(), SpiError> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS STOCK_LEDGER (
+            id BIGSERIAL PRIMARY KEY,
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            movement TEXT NOT NULL CHECK (movement IN ('reserve', 'confirm', 'release', 'replenish', 'adjust')),
+            qty_available_delta INT NOT NULL,
+            qty_reserved_delta INT NOT NULL,
+            instance_id TEXT,
+            reason TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+        CREATE INDEX IF NOT EXISTS stock_ledger_item_idx ON STOCK_LEDGER (seller_id, product_id);
+    "#)?;
+
+    Spi::run(r#"
+        LOCK TABLE stock.stock_items IN SHARE MODE;
+
+        INSERT INTO STOCK_LEDGER (seller_id, product_id, movement, qty_available_delta, qty_reserved_delta, reason)
+        SELECT s.seller_id, s.product_id, 'adjust', s.qty_available, s.qty_reserved, 'opening balance'
+        FROM stock.stock_items s
+        WHERE NOT EXISTS (
+            SELECT 1 FROM STOCK_LEDGER l WHERE l.seller_id = s.seller_id AND l.product_id = s.product_id
+        );
+
+        DROP TRIGGER IF EXISTS stock_ledger_recorder ON stock.stock_items;
+        CREATE TRIGGER stock_ledger_recorder
+        AFTER INSERT OR UPDATE OR DELETE ON stock.stock_items
+        FOR EACH ROW
+        EXECUTE FUNCTION stock_record_movement();
+    "#)?;
+
+    Ok(())
+}
+
+/// Runs `f` with its stock changes attributed to `movement`. The attribution
+/// is cleared afterwards, so later changes in the same transaction (StockMS,
+/// ingestion) are not recorded under it.
+fn with_movement<T>(
+    movement: &str,
+    instance_id: Option<&str>,
+    reason: Option<&str>,
+    f: impl FnOnce() -> Result<T, SpiError>,
+) -> Result<T, SpiError> {
+    let attribute_sql = r#"
+        SELECT set_config('stock.movement', $1, true),
+               set_config('stock.instance_id', $2, true),
+               set_config('stock.reason', $3, true);
+    "#;
+    Spi::run_with_args(
+        attribute_sql,
+        &[movement.into(), instance_id.unwrap_or("").into(), reason.unwrap_or("").into()],
+    )?;
+    let result = f();
+    Spi::run_with_args(attribute_sql, &["".into(), "".into(), "".into()])?;
+    result
+}
+
+#[derive(Debug)]
+enum TriggerError {
+    MissingTuple,
+    Datum(TryFromDatumError),
+    Spi(SpiError),
+}
+
+impl fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerError::MissingTuple => write!(f, "trigger fired without OLD or NEW row"),
+            TriggerError::Datum(e) => write!(f, "cannot read stock column: {e}"),
+            TriggerError::Spi(e) => write!(f, "SPI error in stock trigger: {e}"),
+        }
+    }
+}
+
+impl From<TryFromDatumError> for TriggerError {
+    fn from(e: TryFromDatumError) -> Self {
+        TriggerError::Datum(e)
+    }
+}
+
+impl From<SpiError> for TriggerError {
+    fn from(e: SpiError) -> Self {
+        TriggerError::Spi(e)
+    }
+}
+
+/// Appends the change of one stock item to STOCK_LEDGER. Changes made outside
+/// `with_movement` (ingestion, StockMS product updates and resets) are recorded
+/// as `adjust`; `reconcile` writes are not recorded, as the ledger already
+/// holds the stock they restore.
+#[pg_trigger]
+fn stock_record_movement<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, TriggerError> {
+    let (old, new) = (trigger.old(), trigger.new());
+    let Some(row) = new.as_ref().or(old.as_ref()) else {
+        return Err(TriggerError::MissingTuple);
+    };
+    let seller_id = row.get_by_name::<i32>("seller_id")?;
+    let product_id = row.get_by_name::<i32>("product_id")?;
 
+    let quantities = |tuple: &Option<PgHeapTuple<'a, _>>| -> Result<(i32, i32), TriggerError> {
+        match tuple {
+            Some(tuple) => Ok((
+                tuple.get_by_name::<i32>("qty_available")?.unwrap_or_default(),
+                tuple.get_by_name::<i32>("qty_reserved")?.unwrap_or_default(),
+            )),
+            None => Ok((0, 0)),
+        }
+    };
+    let (old_available, old_reserved) = quantities(&old)?;
+    let (new_available, new_reserved) = quantities(&new)?;
+    let (available_delta, reserved_delta) = (new_available - old_available, new_reserved - old_reserved);
+    if available_delta == 0 && reserved_delta == 0 {
+        return Ok(new.or(old));
+    }
+
+    let (movement, instance_id, reason) = Spi::get_three::<String, String, String>(r#"
+        SELECT nullif(current_setting('stock.movement', true), ''),
+               nullif(current_setting('stock.instance_id', true), ''),
+               nullif(current_setting('stock.reason', true), '');
+    "#)?;
+    let (movement, reason) = match movement {
+        Some(movement) if movement == "reconcile" => return Ok(new.or(old)),
+        Some(movement) => (movement, reason),
+        None if old.is_none() => ("adjust".to_string(), Some("ingestion".to_string())),
+        None => ("adjust".to_string(), reason.or_else(|| Some("external update".to_string()))),
+    };
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO STOCK_LEDGER (seller_id, product_id, movement, qty_available_delta, qty_reserved_delta, instance_id, reason)
+        VALUES ($1, $2, $3, $4, $5, $6, $7);
+        "#,
+        &[
+            seller_id.into(),
+            product_id.into(),
+            movement.into(),
+            available_delta.into(),
+            reserved_delta.into(),
+            instance_id.into(),
+            reason.into(),
+        ],
+    )?;
 
+    Ok(new.or(old))
+}
+
+/// Adds `qty` units to the available stock of an item and returns the new
+/// quantity. Requires `setup_stock_ledger()` for the movement to be recorded.
+#[pg_extern]
+fn stock_replenish(
+    seller_id: i32,
+    product_id: i32,
+    qty: i32,
+    reason: default!(Option<&str>, "NULL"),
+) -> Result<i32, SpiError> {
+    if qty <= 0 {
+        error!("stock_replenish: quantity must be positive, got {qty}");
+    }
+    let replenish_sql = r#"
+        UPDATE stock.stock_items SET qty_available = qty_available + $3, updated_at = now()
+        WHERE seller_id = $1 AND product_id = $2
+        RETURNING qty_available;
+    "#;
+    let qty_available = with_movement("replenish", None, reason, || {
+        match Spi::get_one_with_args::<i32>(replenish_sql, &[seller_id.into(), product_id.into(), qty.into()]) {
+            Err(SpiError::InvalidPosition) => Ok(None),
+            other => other,
+        }
+    })?;
+    match qty_available {
+        Some(qty_available) => Ok(qty_available),
+        None => error!("stock_replenish: no stock item for seller {seller_id}, product {product_id}"),
+    }
+}
+
+/// Recomputes every item's stock from STOCK_LEDGER and returns the items whose
+/// stored quantities differ. With `apply`, those items are reset to the
+/// ledger's quantities.
+#[pg_extern]
+fn stock_reconcile(
+    apply: default!(bool, false),
+) -> Result<
+    TableIterator<'static, (
+        name!(seller_id, i32),
+        name!(product_id, i32),
+        name!(qty_available, i32),
+        name!(ledger_qty_available, i32),
+        name!(qty_reserved, i32),
+        name!(ledger_qty_reserved, i32),
+    )>,
+    SpiError,
+> {
+    let drift_sql = format!(r#"
+        SELECT s.seller_id, s.product_id, s.qty_available, s.qty_reserved,
+               coalesce(l.qty_available, 0)::INT AS ledger_qty_available,
+               coalesce(l.qty_reserved, 0)::INT AS ledger_qty_reserved
+        FROM stock.stock_items s
+        LEFT JOIN (
+            SELECT seller_id, product_id,
+                   sum(qty_available_delta) AS qty_available,
+                   sum(qty_reserved_delta) AS qty_reserved
+            FROM STOCK_LEDGER
+            GROUP BY seller_id, product_id
+        ) l ON l.seller_id = s.seller_id AND l.product_id = s.product_id
+        WHERE s.qty_available IS DISTINCT FROM coalesce(l.qty_available, 0)
+           OR s.qty_reserved IS DISTINCT FROM coalesce(l.qty_reserved, 0)
+        ORDER BY s.seller_id, s.product_id
+        {};
+    "#, if apply { "FOR UPDATE OF s" } else { "" });
+    // Row locks need a read-write SPI connection
+    let drift = Spi::connect_mut(|client| {
+        let mut drift = Vec::new();
+        for row in client.update(&drift_sql, None, &[])? {
+            drift.push((
+                row.get_by_name::<i32, _>("seller_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("product_id")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("qty_available")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("ledger_qty_available")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("qty_reserved")?.unwrap_or_default(),
+                row.get_by_name::<i32, _>("ledger_qty_reserved")?.unwrap_or_default(),
+            ));
+        }
+        Ok::<_, SpiError>(drift)
+    })?;
+
+    if apply {
+        with_movement("reconcile", None, None, || {
+            for &(seller_id, product_id, _, ledger_available, _, ledger_reserved) in &drift {
+                Spi::run_with_args(
+                    r#"
+                    UPDATE stock.stock_items SET qty_available = $3, qty_reserved = $4, updated_at = now()
+                    WHERE seller_id = $1 AND product_id = $2;
+                    "#,
+                    &[seller_id.into(), product_id.into(), ledger_available.into(), ledger_reserved.into()],
+                )?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(TableIterator::new(drift))
+}
+
+////////////////////////////////////////
+// 6. Low-Stock Alerts
+////////////////////////////////////////
+
+/// Creates the alert tables and the trigger that publishes `stock_low` when an
+/// item's free quantity (available minus reserved) drops to its threshold or
+/// runs out. An alerted item is re-armed only once its free quantity clears
+/// the bound it crossed by `recovery_margin`. Requires stock.stock_items to exist.
+#[pg_extern]
+fn setup_stock_alerts(
+    low_threshold: default!(i32, 10),
+    recovery_margin: default!(i32, 5),
+) -> Result<(), SpiError> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS STOCK_ALERT_SETTINGS (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            low_threshold INT NOT NULL CHECK (low_threshold >= 0),
+            recovery_margin INT NOT NULL CHECK (recovery_margin >= 0)
+        );
+        CREATE TABLE IF NOT EXISTS STOCK_ALERT_THRESHOLDS (
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            low_threshold INT NOT NULL CHECK (low_threshold >= 0),
+            PRIMARY KEY (seller_id, product_id)
+        );
+        CREATE TABLE IF NOT EXISTS STOCK_ALERT_STATE (
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            level TEXT NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+            PRIMARY KEY (seller_id, product_id)
+        );
+        CREATE TABLE IF NOT EXISTS STOCK_ALERTS (
+            id BIGSERIAL PRIMARY KEY,
+            seller_id INT NOT NULL,
+            product_id INT NOT NULL,
+            level TEXT NOT NULL,
+            qty_free INT NOT NULL,
+            low_threshold INT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+        );
+    "#)?;
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO STOCK_ALERT_SETTINGS (low_threshold, recovery_margin) VALUES ($1, $2)
+        ON CONFLICT (id) DO UPDATE SET
+            low_threshold = EXCLUDED.low_threshold,
+            recovery_margin = EXCLUDED.recovery_margin;
+        "#,
+        &[low_threshold.into(), recovery_margin.into()],
+    )?;
+
+    Spi::run(r#"
+        DROP TRIGGER IF EXISTS stock_alert_monitor ON stock.stock_items;
+        CREATE TRIGGER stock_alert_monitor
+        AFTER INSERT OR UPDATE OF qty_available, qty_reserved ON stock.stock_items
+        FOR EACH ROW
+        EXECUTE FUNCTION stock_check_alert();
+    "#)?;
+
+    Ok(())
+}
+
+/// Overrides the global threshold for one item; NULL restores the global one.
+/// The item's alert level is re-evaluated against the new threshold.
+#[pg_extern]
+fn set_stock_alert_threshold(
+    seller_id: i32,
+    product_id: i32,
+    low_threshold: Option<i32>,
+) -> Result<(), SpiError> {
+    match low_threshold {
+        Some(low_threshold) => Spi::run_with_args(
+            r#"
+            INSERT INTO STOCK_ALERT_THRESHOLDS (seller_id, product_id, low_threshold) VALUES ($1, $2, $3)
+            ON CONFLICT (seller_id, product_id) DO UPDATE SET low_threshold = EXCLUDED.low_threshold;
+            "#,
+            &[seller_id.into(), product_id.into(), low_threshold.into()],
+        )?,
+        None => Spi::run_with_args(
+            "DELETE FROM STOCK_ALERT_THRESHOLDS WHERE seller_id = $1 AND product_id = $2",
+            &[seller_id.into(), product_id.into()],
+        )?,
+    }
+
+    let qty_free = Spi::get_one_with_args::<i32>(
+        "SELECT qty_available - qty_reserved FROM stock.stock_items WHERE seller_id = $1 AND product_id = $2",
+        &[seller_id.into(), product_id.into()],
+    );
+    match qty_free {
+        Err(SpiError::InvalidPosition) => Ok(()),
+        other => match other? {
+            Some(qty_free) => evaluate_alert(seller_id, product_id, qty_free),
+            None => Ok(()),
+        },
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum StockLevel {
+    Ok,
+    Low,
+    OutOfStock,
+}
+
+impl StockLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            StockLevel::Ok => "ok",
+            StockLevel::Low => "low",
+            StockLevel::OutOfStock => "out_of_stock",
+        }
+    }
+
+    fn parse(level: &str) -> Self {
+        match level {
+            "low" => StockLevel::Low,
+            "out_of_stock" => StockLevel::OutOfStock,
+            _ => StockLevel::Ok,
+        }
+    }
+
+    /// The level after the free quantity became `qty_free`. Each alert keeps
+    /// until the free quantity clears its bound by `recovery_margin`: an
+    /// out-of-stock item until more than `recovery_margin` units are free, a
+    /// low one until it exceeds the threshold by the margin. Otherwise a few
+    /// units coming and going around a bound would alert on every swing.
+    fn next(self, qty_free: i32, low_threshold: i32, recovery_margin: i32) -> Self {
+        if qty_free <= 0 || (self == StockLevel::OutOfStock && qty_free <= recovery_margin) {
+            StockLevel::OutOfStock
+        } else if qty_free <= low_threshold {
+            StockLevel::Low
+        } else if qty_free > low_threshold + recovery_margin || self == StockLevel::Ok {
+            StockLevel::Ok
+        } else {
+            StockLevel::Low
+        }
+    }
+}
+
+/// Moves an item between alert levels (see `evaluate_alert`) when its free
+/// quantity changed.
+#[pg_trigger]
+fn stock_check_alert<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, TriggerError> {
+    let Some(new) = trigger.new() else {
+        return Err(TriggerError::MissingTuple);
+    };
+    let free = |tuple: &PgHeapTuple<'a, _>| -> Result<i32, TriggerError> {
+        Ok(tuple.get_by_name::<i32>("qty_available")?.unwrap_or_default()
+            - tuple.get_by_name::<i32>("qty_reserved")?.unwrap_or_default())
+    };
+    let qty_free = free(&new)?;
+    if let Some(old) = trigger.old() {
+        if free(&old)? == qty_free {
+            return Ok(Some(new));
+        }
+    }
+    let seller_id = new.get_by_name::<i32>("seller_id")?.unwrap_or_default();
+    let product_id = new.get_by_name::<i32>("product_id")?.unwrap_or_default();
+
+    evaluate_alert(seller_id, product_id, qty_free)?;
+    Ok(Some(new))
+}
+
+/// Stores the item's level for `qty_free` and publishes `stock_low` (recorded
+/// in STOCK_ALERTS) whenever it gets worse. Recoveries only re-arm the item.
+fn evaluate_alert(seller_id: i32, product_id: i32, qty_free: i32) -> Result<(), SpiError> {
+    let settings = Spi::get_three_with_args::<i32, i32, String>(
+        r#"
+        SELECT coalesce(t.low_threshold, s.low_threshold), s.recovery_margin, coalesce(a.level, 'ok')
+        FROM STOCK_ALERT_SETTINGS s
+        LEFT JOIN STOCK_ALERT_THRESHOLDS t ON t.seller_id = $1 AND t.product_id = $2
+        LEFT JOIN STOCK_ALERT_STATE a ON a.seller_id = $1 AND a.product_id = $2;
+        "#,
+        &[seller_id.into(), product_id.into()],
+    );
+    let (low_threshold, recovery_margin, level) = match settings {
+        Err(SpiError::InvalidPosition) => (None, None, None),
+        other => other?,
+    };
+    let (Some(low_threshold), Some(recovery_margin)) = (low_threshold, recovery_margin) else {
+        return Ok(());
+    };
+    let level = StockLevel::parse(level.as_deref().unwrap_or("ok"));
+    let next = level.next(qty_free, low_threshold, recovery_margin);
+    if next == level {
+        return Ok(());
+    }
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO STOCK_ALERT_STATE (seller_id, product_id, level) VALUES ($1, $2, $3)
+        ON CONFLICT (seller_id, product_id) DO UPDATE SET level = EXCLUDED.level, updated_at = clock_timestamp();
+        "#,
+        &[seller_id.into(), product_id.into(), next.as_str().into()],
+    )?;
+
+    if next > level {
+        Spi::run_with_args(
+            r#"
+            INSERT INTO STOCK_ALERTS (seller_id, product_id, level, qty_free, low_threshold)
+            VALUES ($1, $2, $3, $4, $5);
+            "#,
+            &[
+                seller_id.into(),
+                product_id.into(),
+                next.as_str().into(),
+                qty_free.into(),
+                low_threshold.into(),
+            ],
+        )?;
+        notify("stock_low", &json!({
+            "sellerId": seller_id,
+            "productId": product_id,
+            "level": next.as_str(),
+            "qtyFree": qty_free,
+            "lowThreshold": low_threshold,
+        }))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use crate::StockLevel;
+    use pgrx::prelude::*;
+    use pgrx::spi::{Spi, SpiError};
+    use pgrx::JsonB;
+    use serde_json::json;
+
+    // The subset of StockMS' stock_items the extension touches
+    fn setup_stock_items(qty_available: i32) -> Result<(), SpiError> {
+        Spi::run(r#"
+            CREATE SCHEMA IF NOT EXISTS stock;
+            CREATE TABLE stock.stock_items (
+                seller_id INT NOT NULL,
+                product_id INT NOT NULL,
+                qty_available INT NOT NULL,
+                qty_reserved INT NOT NULL DEFAULT 0,
+                order_count INT NOT NULL DEFAULT 0,
+                version TEXT NOT NULL DEFAULT '1',
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (seller_id, product_id)
+            );
+        "#)?;
+        Spi::run_with_args(
+            "INSERT INTO stock.stock_items (seller_id, product_id, qty_available) VALUES (1, 1, $1)",
+            &[qty_available.into()],
+        )
+    }
+
+    fn stock() -> Result<(Option<i32>, Option<i32>), SpiError> {
+        Spi::get_two("SELECT qty_available, qty_reserved FROM stock.stock_items")
+    }
+
+    fn set_available(qty_available: i32) -> Result<(), SpiError> {
+        Spi::run_with_args("UPDATE stock.stock_items SET qty_available = $1", &[qty_available.into()])
+    }
+
+    #[pg_test]
+    fn ledger_records_each_movement() -> Result<(), SpiError> {
+        crate::setup_stock()?;
+        setup_stock_items(10)?;
+        crate::setup_stock_ledger()?;
+
+        assert_eq!(crate::stock_replenish(1, 1, 5, Some("restock"))?, 15);
+        crate::stock_reserve(JsonB(json!({
+            "customerCheckout": { "CustomerId": 1 },
+            "items": [{ "SellerId": 1, "ProductId": 1, "Quantity": 2, "Version": "1" }],
+            "instanceId": "i-1",
+        })), true)?;
+        assert_eq!(crate::stock_cancel("i-1")?, 1);
+
+        let movements = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(movement || ':' || qty_available_delta || ':' || qty_reserved_delta ORDER BY id) FROM STOCK_LEDGER",
+        )?;
+        assert_eq!(movements, Some(vec![
+            "adjust:10:0".to_string(),
+            "replenish:5:0".to_string(),
+            "reserve:0:2".to_string(),
+            "release:0:-2".to_string(),
+        ]));
+        assert_eq!(stock()?, (Some(15), Some(0)));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn reconcile_restores_ledger_quantities() -> Result<(), SpiError> {
+        setup_stock_items(10)?;
+        crate::setup_stock_ledger()?;
+        // a write the ledger never saw
+        Spi::run(r#"
+            ALTER TABLE stock.stock_items DISABLE TRIGGER stock_ledger_recorder;
+            UPDATE stock.stock_items SET qty_available = 3;
+            ALTER TABLE stock.stock_items ENABLE TRIGGER stock_ledger_recorder;
+        "#)?;
+
+        let drift: Vec<_> = crate::stock_reconcile(false)?.collect();
+        assert_eq!(drift, vec![(1, 1, 3, 10, 0, 0)]);
+        assert_eq!(stock()?, (Some(3), Some(0)));
+
+        assert_eq!(crate::stock_reconcile(true)?.count(), 1);
+        assert_eq!(stock()?, (Some(10), Some(0)));
+        assert_eq!(crate::stock_reconcile(false)?.count(), 0);
+        assert_eq!(Spi::get_one::<i64>("SELECT count(*) FROM STOCK_LEDGER")?, Some(1));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn stock_level_keeps_alerts_until_recovered() {
+        use StockLevel::*;
+        // threshold 10, margin 5
+        assert!(Ok.next(11, 10, 5) == Ok);
+        assert!(Ok.next(10, 10, 5) == Low);
+        assert!(Low.next(15, 10, 5) == Low);
+        assert!(Low.next(16, 10, 5) == Ok);
+        assert!(Low.next(0, 10, 5) == OutOfStock);
+        assert!(OutOfStock.next(1, 10, 5) == OutOfStock);
+        assert!(OutOfStock.next(5, 10, 5) == OutOfStock);
+        assert!(OutOfStock.next(6, 10, 5) == Low);
+        assert!(OutOfStock.next(16, 10, 5) == Ok);
+    }
+
+    #[pg_test]
+    fn alerts_fire_once_per_worsening() -> Result<(), SpiError> {
+        setup_stock_items(20)?;
+        crate::setup_stock_alerts(10, 5)?;
+
+        // churn around each bound alerts only on the first crossing
+        for qty_available in [10, 11, 10, 0, 1, 0, 1, 0] {
+            set_available(qty_available)?;
+        }
+        let alerts = Spi::get_one::<Vec<String>>("SELECT array_agg(level ORDER BY id) FROM STOCK_ALERTS")?;
+        assert_eq!(alerts, Some(vec!["low".to_string(), "out_of_stock".to_string()]));
+
+        set_available(16)?;
+        assert_eq!(Spi::get_one::<String>("SELECT level FROM STOCK_ALERT_STATE")?.as_deref(), Some("ok"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn threshold_change_reevaluates_level() -> Result<(), SpiError> {
+        setup_stock_items(20)?;
+        crate::setup_stock_alerts(10, 5)?;
+
+        crate::set_stock_alert_threshold(1, 1, Some(25))?;
+        assert_eq!(Spi::get_one::<String>("SELECT level FROM STOCK_ALERT_STATE")?.as_deref(), Some("low"));
+        assert_eq!(Spi::get_one::<i64>("SELECT count(*) FROM STOCK_ALERTS")?, Some(1));
+
+        crate::set_stock_alert_threshold(1, 1, None)?;
+        assert_eq!(Spi::get_one::<String>("SELECT level FROM STOCK_ALERT_STATE")?.as_deref(), Some("ok"));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}
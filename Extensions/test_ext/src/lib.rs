@@ -1,7 +1,15 @@
+use pgrx::bgworkers::BackgroundWorkerBuilder;
+use pgrx::datum::to_timestamp;
+use pgrx::pg_sys::{self, panic::register_pg_guard_panic_hook};
 use pgrx::prelude::*;
+use pgrx::JsonB;
+use std::collections::{BTreeMap, HashMap};
 
 ::pgrx::pg_module_magic!();
 
+////////////////////////////////////////
+// 1. Setup Mark Triggers
+////////////////////////////////////////
 
 #[pg_extern]
 fn setup_test() -> Result<(), spi::Error> {
@@ -90,4 +98,931 @@ fn setup_test() -> Result<(), spi::Error> {
     Spi::run(create_checkout_sql)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+////////////////////////////////////////
+// 2. Latency and Throughput Analytics
+////////////////////////////////////////
+
+static MARKS_SQL: &str = r#"
+    SELECT transaction_type, instance_id, mark_status,
+           EXTRACT(EPOCH FROM created_at)::FLOAT8 AS created_at,
+           EXTRACT(EPOCH FROM submitted_at)::FLOAT8 AS submitted_at
+    FROM (
+        SELECT transaction_type, instance_id, mark_status, created_at, submitted_at, run_id FROM CHECKOUT
+        UNION ALL
+        SELECT transaction_type, instance_id, mark_status, created_at, submitted_at, run_id FROM PRICEUPDATE
+        UNION ALL
+        SELECT transaction_type, instance_id, mark_status, created_at, submitted_at, run_id FROM PRODUCTUPDATE
+    ) marks
+    WHERE $1::BIGINT IS NULL OR run_id = $1;
+"#;
+
+/// All marks of one transaction folded together. Times are epoch seconds.
+struct TransactionSummary {
+    transaction_type: String,
+    submitted_at: Option<f64>,
+    final_mark_at: f64,
+    final_status: String,
+}
+
+fn load_transactions(run_id: Option<i64>) -> Result<Vec<TransactionSummary>, spi::Error> {
+    let mut transactions: HashMap<(String, String), TransactionSummary> = HashMap::new();
+
+    Spi::connect(|client| {
+        for row in client.select(MARKS_SQL, None, &[run_id.into()])? {
+            let transaction_type: String = row.get_by_name("transaction_type")?.unwrap_or_default();
+            let instance_id: String = row.get_by_name("instance_id")?.unwrap_or_default();
+            let mark_status: String = row.get_by_name("mark_status")?.unwrap_or_default();
+            let created_at: f64 = row.get_by_name("created_at")?.unwrap_or_default();
+            let submitted_at: Option<f64> = row.get_by_name("submitted_at")?;
+
+            let summary = transactions
+                .entry((transaction_type.clone(), instance_id))
+                .or_insert_with(|| TransactionSummary {
+                    transaction_type,
+                    submitted_at: None,
+                    final_mark_at: created_at,
+                    final_status: mark_status.clone(),
+                });
+
+            // The earliest submission time reported by any participant wins
+            if let Some(ts) = submitted_at {
+                summary.submitted_at = Some(summary.submitted_at.map_or(ts, |s| s.min(ts)));
+            }
+            if created_at >= summary.final_mark_at {
+                summary.final_mark_at = created_at;
+                summary.final_status = mark_status;
+            }
+        }
+        Ok::<_, spi::Error>(())
+    })?;
+
+    Ok(transactions.into_values().collect())
+}
+
+/// Linear interpolation between the closest ranks of an ascending slice.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+#[pg_extern]
+fn mark_throughput(
+    interval_secs: default!(f64, 1.0),
+    run_id: default!(Option<i64>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(transaction_type, String),
+            name!(interval_start, TimestampWithTimeZone),
+            name!(completed, i64),
+            name!(succeeded, i64),
+            name!(per_second, f64),
+        ),
+    >,
+    spi::Error,
+> {
+    if interval_secs <= 0.0 {
+        error!("interval_secs must be positive, got {interval_secs}");
+    }
+
+    // (transaction_type, bucket) -> (completed, succeeded)
+    let mut buckets: BTreeMap<(String, i64), (i64, i64)> = BTreeMap::new();
+    for txn in load_transactions(run_id)? {
+        let bucket = (txn.final_mark_at / interval_secs).floor() as i64;
+        let counts = buckets.entry((txn.transaction_type, bucket)).or_default();
+        counts.0 += 1;
+        if txn.final_status == "SUCCESS" {
+            counts.1 += 1;
+        }
+    }
+
+    let rows = buckets
+        .into_iter()
+        .map(move |((transaction_type, bucket), (completed, succeeded))| {
+            (
+                transaction_type,
+                to_timestamp(bucket as f64 * interval_secs),
+                completed,
+                succeeded,
+                completed as f64 / interval_secs,
+            )
+        })
+        .collect::<Vec<_>>();
+    Ok(TableIterator::new(rows))
+}
+
+#[pg_extern]
+fn mark_latency_percentiles(
+    percentiles: default!(Vec<f64>, "ARRAY[0.5, 0.9, 0.99]"),
+    run_id: default!(Option<i64>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(transaction_type, String),
+            name!(samples, i64),
+            name!(percentile, f64),
+            name!(latency_ms, f64),
+        ),
+    >,
+    spi::Error,
+> {
+    // Only transactions whose caller passed submitted_at can be measured end-to-end
+    let mut latencies: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for txn in load_transactions(run_id)? {
+        if let Some(submitted_at) = txn.submitted_at {
+            latencies
+                .entry(txn.transaction_type)
+                .or_default()
+                .push((txn.final_mark_at - submitted_at) * 1000.0);
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (transaction_type, mut samples) in latencies {
+        samples.sort_by(|a, b| a.total_cmp(b));
+        for &p in &percentiles {
+            rows.push((transaction_type.clone(), samples.len() as i64, p, percentile(&samples, p)));
+        }
+    }
+    Ok(TableIterator::new(rows))
+}
+
+////////////////////////////////////////
+// 3. Cross-Service Transaction Tracker
+////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq)]
+enum TransactionState {
+    InProgress,
+    Completed,
+    Aborted,
+    Stuck,
+}
+
+impl TransactionState {
+    fn as_str(self) -> &'static str {
+        match self {
+            TransactionState::InProgress => "in_progress",
+            TransactionState::Completed => "completed",
+            TransactionState::Aborted => "aborted",
+            TransactionState::Stuck => "stuck",
+        }
+    }
+
+    /// Any non-SUCCESS mark aborts the transaction; it completes once every
+    /// expected participant has marked SUCCESS (or on the first SUCCESS when
+    /// no participants are registered for its type).
+    fn derive(expected: &[String], succeeded: &[String], failed: bool) -> Self {
+        if failed {
+            TransactionState::Aborted
+        } else if expected.iter().all(|p| succeeded.contains(p)) && !succeeded.is_empty() {
+            TransactionState::Completed
+        } else {
+            TransactionState::InProgress
+        }
+    }
+}
+
+/// Requires the services' mark tables and stock_ext's STOCK_RESERVATIONS.
+#[pg_extern]
+fn setup_transaction_tracker() -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS TRANSACTION_PARTICIPANTS (
+            transaction_type TEXT NOT NULL,
+            participant TEXT NOT NULL,
+            PRIMARY KEY (transaction_type, participant)
+        );
+
+        -- Services that mark SUCCESS on the happy path; the others only mark failures
+        INSERT INTO TRANSACTION_PARTICIPANTS (transaction_type, participant) VALUES
+            ('CUSTOMER_SESSION', 'shipment'),
+            ('PRICE_UPDATE', 'cart'),
+            ('UPDATE_PRODUCT', 'stock')
+        ON CONFLICT DO NOTHING;
+    "#)?;
+
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS TRANSACTION_TRACKER (
+            instance_id TEXT NOT NULL,
+            transaction_type TEXT NOT NULL,
+            state TEXT NOT NULL,
+            succeeded TEXT[] NOT NULL DEFAULT '{}',
+            failed_by TEXT,
+            actor_id TEXT NOT NULL,
+            last_status TEXT,
+            started_at TIMESTAMPTZ NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL,
+            finished_at TIMESTAMPTZ,
+            PRIMARY KEY (transaction_type, instance_id)
+        );
+        CREATE INDEX IF NOT EXISTS transaction_tracker_open_idx
+            ON TRANSACTION_TRACKER (updated_at) WHERE state = 'in_progress';
+    "#)?;
+
+    Spi::run(r#"
+        CREATE OR REPLACE FUNCTION track_insert_mark()
+        RETURNS TRIGGER AS
+        $$
+        BEGIN
+        -- TG_ARGV[0] names the table's actor column
+        PERFORM track_transaction_mark(
+            NEW.instance_id, NEW.transaction_type, to_jsonb(NEW)->>TG_ARGV[0], NEW.db, NEW.mark_status
+        );
+        RETURN NEW;
+        END;
+        $$ LANGUAGE plpgsql;
+
+        CREATE OR REPLACE TRIGGER checkout_tracker_trigger
+        AFTER INSERT ON CHECKOUT
+        FOR EACH ROW
+        EXECUTE FUNCTION track_insert_mark('customer_id');
+
+        CREATE OR REPLACE TRIGGER priceupdate_tracker_trigger
+        AFTER INSERT ON PRICEUPDATE
+        FOR EACH ROW
+        EXECUTE FUNCTION track_insert_mark('seller_id');
+
+        CREATE OR REPLACE TRIGGER productupdate_tracker_trigger
+        AFTER INSERT ON PRODUCTUPDATE
+        FOR EACH ROW
+        EXECUTE FUNCTION track_insert_mark('seller_id');
+    "#)?;
+
+    // A checkout writes no mark until shipment's SUCCESS or a failure, so it is
+    // opened by its first stock reservation instead
+    Spi::run(r#"
+        CREATE OR REPLACE FUNCTION track_stock_reservation()
+        RETURNS TRIGGER AS
+        $$
+        BEGIN
+        PERFORM track_transaction_start(NEW.instance_id, 'CUSTOMER_SESSION', NEW.customer_id::TEXT);
+        RETURN NEW;
+        END;
+        $$ LANGUAGE plpgsql;
+
+        CREATE OR REPLACE TRIGGER stock_reservation_tracker_trigger
+        AFTER INSERT ON STOCK_RESERVATIONS
+        FOR EACH ROW
+        EXECUTE FUNCTION track_stock_reservation();
+    "#)?;
+
+    Ok(())
+}
+
+#[pg_extern]
+fn set_transaction_participants(transaction_type: &str, participants: Vec<String>) -> Result<(), spi::Error> {
+    Spi::run_with_args(
+        "DELETE FROM TRANSACTION_PARTICIPANTS WHERE transaction_type = $1",
+        &[transaction_type.into()],
+    )?;
+    Spi::run_with_args(
+        r#"
+        INSERT INTO TRANSACTION_PARTICIPANTS (transaction_type, participant)
+        SELECT $1, unnest($2::TEXT[])
+        ON CONFLICT DO NOTHING;
+        "#,
+        &[transaction_type.into(), participants.into()],
+    )?;
+    Ok(())
+}
+
+fn expected_participants(transaction_type: &str) -> Result<Vec<String>, spi::Error> {
+    let participants = Spi::get_one_with_args::<Vec<String>>(
+        "SELECT array_agg(participant) FROM TRANSACTION_PARTICIPANTS WHERE transaction_type = $1",
+        &[transaction_type.into()],
+    )?;
+    Ok(participants.unwrap_or_default())
+}
+
+/// Opens an in-progress transaction before any of its marks exist, so the
+/// tracker and the timeout worker see it from the start. Called by the
+/// STOCK_RESERVATIONS trigger for checkouts reserved by stock_ext; drivers
+/// whose stock is reserved by StockMS call it when they submit the checkout.
+#[pg_extern]
+fn track_transaction_start(instance_id: &str, transaction_type: &str, actor_id: &str) -> Result<(), spi::Error> {
+    Spi::run_with_args(
+        r#"
+        INSERT INTO TRANSACTION_TRACKER
+            (instance_id, transaction_type, state, actor_id, started_at, updated_at)
+        VALUES ($1, $2, 'in_progress', $3, clock_timestamp(), clock_timestamp())
+        ON CONFLICT (transaction_type, instance_id) DO NOTHING;
+        "#,
+        &[instance_id.into(), transaction_type.into(), actor_id.into()],
+    )
+}
+
+/// Folds one mark into the tracker. Called by the mark table triggers, so it
+/// runs inside the transaction that wrote the mark.
+#[pg_extern]
+fn track_transaction_mark(
+    instance_id: &str, transaction_type: &str, actor_id: &str, participant: &str, mark_status: &str
+) -> Result<(), spi::Error> {
+    // The upsert locks the tracker row, serialising concurrent marks of one transaction
+    let upsert_sql = r#"
+        INSERT INTO TRANSACTION_TRACKER AS t
+            (instance_id, transaction_type, state, succeeded, failed_by, actor_id, last_status, started_at, updated_at)
+        VALUES (
+            $1, $2, 'in_progress',
+            CASE WHEN $4 = 'SUCCESS' THEN ARRAY[$3] ELSE '{}'::TEXT[] END,
+            CASE WHEN $4 = 'SUCCESS' THEN NULL ELSE $3 END,
+            $5, $4, clock_timestamp(), clock_timestamp()
+        )
+        ON CONFLICT (transaction_type, instance_id) DO UPDATE SET
+            succeeded = CASE
+                WHEN $4 = 'SUCCESS' AND NOT ($3 = ANY(t.succeeded)) THEN array_append(t.succeeded, $3)
+                ELSE t.succeeded
+            END,
+            failed_by = COALESCE(t.failed_by, EXCLUDED.failed_by),
+            last_status = $4,
+            updated_at = clock_timestamp()
+        RETURNING state, succeeded, failed_by IS NOT NULL;
+    "#;
+    let (state, succeeded, failed) = Spi::get_three_with_args::<String, Vec<String>, bool>(
+        upsert_sql,
+        &[
+            instance_id.into(), transaction_type.into(), participant.into(),
+            mark_status.into(), actor_id.into(),
+        ],
+    )?;
+
+    // Terminal states are final; late marks only update the participant list
+    if state.as_deref() != Some(TransactionState::InProgress.as_str()) {
+        return Ok(());
+    }
+
+    let expected = expected_participants(transaction_type)?;
+    let next = TransactionState::derive(&expected, &succeeded.unwrap_or_default(), failed.unwrap_or(false));
+    if next == TransactionState::InProgress {
+        return Ok(());
+    }
+
+    Spi::run_with_args(
+        r#"
+        UPDATE TRANSACTION_TRACKER SET state = $3, finished_at = clock_timestamp()
+        WHERE transaction_type = $2 AND instance_id = $1;
+        "#,
+        &[instance_id.into(), transaction_type.into(), next.as_str().into()],
+    )?;
+    Spi::run_with_args(
+        r#"
+        SELECT pg_notify('transaction_terminal', json_build_object(
+            'tid', $1,
+            'type', $2,
+            'state', $3,
+            'status', $4,
+            'source', $5
+        )::text);
+        "#,
+        &[
+            instance_id.into(), transaction_type.into(), next.as_str().into(),
+            mark_status.into(), participant.into(),
+        ],
+    )?;
+    Ok(())
+}
+
+#[pg_extern]
+fn transaction_states(
+    stuck_after_secs: default!(f64, 30.0),
+    instance_id: default!(Option<&str>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(instance_id, String),
+            name!(transaction_type, String),
+            name!(state, String),
+            name!(succeeded, Vec<String>),
+            name!(missing, Vec<String>),
+            name!(failed_by, Option<String>),
+            name!(started_at, TimestampWithTimeZone),
+            name!(updated_at, TimestampWithTimeZone),
+        ),
+    >,
+    spi::Error,
+> {
+    let mut expected: HashMap<String, Vec<String>> = HashMap::new();
+    let mut rows = Vec::new();
+
+    Spi::connect(|client| {
+        for row in client.select(
+            "SELECT transaction_type, participant FROM TRANSACTION_PARTICIPANTS",
+            None,
+            &[],
+        )? {
+            let transaction_type: String = row.get_by_name("transaction_type")?.unwrap_or_default();
+            let participant: String = row.get_by_name("participant")?.unwrap_or_default();
+            expected.entry(transaction_type).or_default().push(participant);
+        }
+
+        let select_sql = r#"
+            SELECT instance_id, transaction_type, state, succeeded, failed_by, started_at, updated_at,
+                   EXTRACT(EPOCH FROM clock_timestamp() - updated_at)::FLOAT8 AS idle_secs
+            FROM TRANSACTION_TRACKER
+            WHERE $1::TEXT IS NULL OR instance_id = $1
+            ORDER BY started_at;
+        "#;
+        for row in client.select(select_sql, None, &[instance_id.into()])? {
+            let transaction_type: String = row.get_by_name("transaction_type")?.unwrap_or_default();
+            let succeeded: Vec<String> = row.get_by_name("succeeded")?.unwrap_or_default();
+            let mut state: String = row.get_by_name("state")?.unwrap_or_default();
+            let idle_secs: f64 = row.get_by_name("idle_secs")?.unwrap_or_default();
+
+            // Stuck is not stored: it is an in-progress transaction that stopped receiving marks
+            if state == TransactionState::InProgress.as_str() && idle_secs > stuck_after_secs {
+                state = TransactionState::Stuck.as_str().to_string();
+            }
+
+            let missing = expected
+                .get(&transaction_type)
+                .map(|participants| {
+                    participants.iter().filter(|p| !succeeded.contains(p)).cloned().collect()
+                })
+                .unwrap_or_default();
+
+            rows.push((
+                row.get_by_name::<String, _>("instance_id")?.unwrap_or_default(),
+                transaction_type,
+                state,
+                succeeded,
+                missing,
+                row.get_by_name("failed_by")?,
+                row.get_by_name::<TimestampWithTimeZone, _>("started_at")?.expect("started_at is NOT NULL"),
+                row.get_by_name::<TimestampWithTimeZone, _>("updated_at")?.expect("updated_at is NOT NULL"),
+            ));
+        }
+        Ok::<_, spi::Error>(())
+    })?;
+
+    Ok(TableIterator::new(rows))
+}
+
+////////////////////////////////////////
+// 4. Transaction Timeout Worker
+////////////////////////////////////////
+
+const DEFAULT_SCAN_INTERVAL_SECS: f64 = 5.0;
+
+/// Creates (or updates) the timeout settings read by the timeout worker on every scan.
+#[pg_extern]
+fn setup_transaction_timeout(
+    timeout_secs: default!(f64, 30.0),
+    scan_interval_secs: default!(f64, 5.0),
+) -> Result<(), spi::Error> {
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS TRANSACTION_TIMEOUT_SETTINGS (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            timeout_secs FLOAT8 NOT NULL,
+            scan_interval_secs FLOAT8 NOT NULL
+        );
+    "#)?;
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO TRANSACTION_TIMEOUT_SETTINGS (id, timeout_secs, scan_interval_secs)
+        VALUES (TRUE, $1, $2)
+        ON CONFLICT (id) DO UPDATE SET
+            timeout_secs = EXCLUDED.timeout_secs,
+            scan_interval_secs = EXCLUDED.scan_interval_secs;
+        "#,
+        &[timeout_secs.into(), scan_interval_secs.into()],
+    )?;
+    Ok(())
+}
+
+/// Requires `setup_transaction_tracker()`, and mark tables created by the
+/// services' setup functions, which declare the `reason` the ABORT marks carry.
+/// Runs with the default timeout until `setup_transaction_timeout()` is called.
+#[pg_extern]
+fn start_transaction_timeout_worker() -> Result<(), String> {
+    BackgroundWorkerBuilder::new("transaction_timeout_worker")
+        .set_library("test_ext")
+        .set_function("timeout_bgworker")
+        .enable_spi_access()
+        .load_dynamic();
+    Ok(())
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn timeout_bgworker(_arg: pg_sys::Datum) {
+    register_pg_guard_panic_hook();
+
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnection(
+            b"postgres\0".as_ptr() as *const i8,
+            b"ucloud\0".as_ptr() as *const i8,
+            0,
+        );
+    }
+
+    marks::run_periodic("Timeout BGW", "aborted stalled transaction(s)", DEFAULT_SCAN_INTERVAL_SECS, abort_stalled_transactions);
+}
+
+/// Mark table the driver listens on for each transaction type, with its actor column.
+fn mark_table(transaction_type: &str) -> (&'static str, &'static str) {
+    match transaction_type {
+        "PRICE_UPDATE" => ("PRICEUPDATE", "seller_id"),
+        "UPDATE_PRODUCT" => ("PRODUCTUPDATE", "seller_id"),
+        _ => ("CHECKOUT", "customer_id"),
+    }
+}
+
+/// Writes an ABORT mark for every in-progress transaction idle beyond the
+/// timeout. Returns the number aborted and the configured scan interval.
+fn abort_stalled_transactions() -> Result<(i64, f64), spi::Error> {
+    let settings = Spi::get_two::<f64, f64>(
+        "SELECT timeout_secs, scan_interval_secs FROM TRANSACTION_TIMEOUT_SETTINGS",
+    );
+    let (timeout_secs, scan_interval_secs) = match settings {
+        Err(spi::Error::InvalidPosition) => (None, None),
+        other => other?,
+    };
+    let timeout_secs = timeout_secs.unwrap_or(30.0);
+    let scan_interval_secs = scan_interval_secs.unwrap_or(DEFAULT_SCAN_INTERVAL_SECS);
+
+    // SKIP LOCKED leaves transactions whose marks are being written right now alone
+    let stalled_sql = r#"
+        SELECT instance_id, transaction_type, actor_id, succeeded
+        FROM TRANSACTION_TRACKER
+        WHERE state = 'in_progress'
+          AND updated_at < clock_timestamp() - make_interval(secs => $1)
+        ORDER BY updated_at
+        FOR UPDATE SKIP LOCKED;
+    "#;
+    let stalled = Spi::connect_mut(|client| {
+        let mut stalled = Vec::new();
+        for row in client.update(stalled_sql, None, &[timeout_secs.into()])? {
+            stalled.push((
+                row.get_by_name::<String, _>("instance_id")?.unwrap_or_default(),
+                row.get_by_name::<String, _>("transaction_type")?.unwrap_or_default(),
+                row.get_by_name::<String, _>("actor_id")?.unwrap_or_default(),
+                row.get_by_name::<Vec<String>, _>("succeeded")?.unwrap_or_default(),
+            ));
+        }
+        Ok::<_, spi::Error>(stalled)
+    })?;
+
+    let mut aborted = 0;
+    for (instance_id, transaction_type, actor_id, succeeded) in stalled {
+        let missing: Vec<String> = expected_participants(&transaction_type)?
+            .into_iter()
+            .filter(|p| !succeeded.contains(p))
+            .collect();
+        let reason = format!(
+            "timed out after {timeout_secs}s waiting for: {}",
+            if missing.is_empty() { "final mark".to_string() } else { missing.join(", ") }
+        );
+
+        let (table, actor_column) = mark_table(&transaction_type);
+        let insert_sql = format!(
+            "INSERT INTO {table} (stream_id, instance_id, transaction_type, {actor_column}, mark_status, db, reason) \
+             VALUES ($1, $2, $3, $4, 'ABORT', 'timeout', $5)"
+        );
+        // The services publish each transaction type's marks on its own stream
+        Spi::run_with_args(&insert_sql, &[
+            format!("TransactionMark_{transaction_type}").into(),
+            instance_id.as_str().into(),
+            transaction_type.as_str().into(),
+            actor_id.into(),
+            reason.into(),
+        ])?;
+        aborted += 1;
+    }
+
+    Ok((aborted, scan_interval_secs))
+}
+
+////////////////////////////////////////
+// 5. Mark Retention
+////////////////////////////////////////
+
+static MARK_TABLES: [&str; 3] = ["CHECKOUT", "PRICEUPDATE", "PRODUCTUPDATE"];
+
+/// Creates (or updates) the retention settings read by the retention worker on every run.
+#[pg_extern]
+fn setup_mark_retention(
+    horizon_secs: default!(f64, 3600.0),
+    interval_secs: default!(f64, 60.0),
+    archive: default!(bool, false),
+) -> Result<(), spi::Error> {
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
+    Spi::run(r#"
+        CREATE TABLE IF NOT EXISTS MARK_RETENTION_SETTINGS (
+            id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+            horizon_secs FLOAT8 NOT NULL,
+            interval_secs FLOAT8 NOT NULL,
+            archive BOOLEAN NOT NULL
+        );
+    "#)?;
+
+    Spi::run_with_args(
+        r#"
+        INSERT INTO MARK_RETENTION_SETTINGS (id, horizon_secs, interval_secs, archive)
+        VALUES (TRUE, $1, $2, $3)
+        ON CONFLICT (id) DO UPDATE SET
+            horizon_secs = EXCLUDED.horizon_secs,
+            interval_secs = EXCLUDED.interval_secs,
+            archive = EXCLUDED.archive;
+        "#,
+        &[horizon_secs.into(), interval_secs.into(), archive.into()],
+    )?;
+    Ok(())
+}
+
+/// Purges the marks of every source written before `before`, together with
+/// the tracker rows of transactions last updated before it.
+#[pg_extern]
+fn purge_marks(before: TimestampWithTimeZone, archive: default!(bool, false)) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, None, Some(before), None, archive)?;
+    }
+
+    if Spi::get_one::<bool>("SELECT to_regclass('TRANSACTION_TRACKER') IS NOT NULL")?.unwrap_or(false) {
+        Spi::run_with_args(
+            "DELETE FROM TRANSACTION_TRACKER WHERE updated_at < $1",
+            &[before.into()],
+        )?;
+    }
+    Ok(purged)
+}
+
+/// Requires `setup_mark_retention()`.
+#[pg_extern]
+fn start_mark_retention_worker() -> Result<(), String> {
+    BackgroundWorkerBuilder::new("mark_retention_worker")
+        .set_library("test_ext")
+        .set_function("retention_bgworker")
+        .enable_spi_access()
+        .load_dynamic();
+    Ok(())
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn retention_bgworker(_arg: pg_sys::Datum) {
+    register_pg_guard_panic_hook();
+
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnection(
+            b"postgres\0".as_ptr() as *const i8,
+            b"ucloud\0".as_ptr() as *const i8,
+            0,
+        );
+    }
+
+    marks::run_periodic("Retention BGW", "mark(s) purged", DEFAULT_SCAN_INTERVAL_SECS, apply_mark_retention);
+}
+
+fn apply_mark_retention() -> Result<(i64, f64), spi::Error> {
+    let settings = Spi::get_three::<f64, f64, bool>(
+        "SELECT horizon_secs, interval_secs, archive FROM MARK_RETENTION_SETTINGS",
+    );
+    let (horizon_secs, interval_secs, archive) = match settings {
+        Err(spi::Error::InvalidPosition) => (None, None, None),
+        other => other?,
+    };
+    let before = Spi::get_one_with_args::<TimestampWithTimeZone>(
+        "SELECT clock_timestamp() - make_interval(secs => $1)",
+        &[horizon_secs.unwrap_or(3600.0).into()],
+    )?
+    .expect("clock_timestamp() is never NULL");
+
+    let purged = purge_marks(before, archive.unwrap_or(false))?;
+    Ok((purged, interval_secs.unwrap_or(DEFAULT_SCAN_INTERVAL_SECS)))
+}
+
+////////////////////////////////////////
+// 6. Benchmark Runs
+////////////////////////////////////////
+
+/// Every mark inserted while a run is open is stamped with its id through
+/// the `run_id` column default the services' setups declare.
+#[pg_extern]
+fn setup_benchmark_runs() -> Result<(), spi::Error> {
+    Spi::run(marks::RUN_STAMP_SQL)?;
+    Spi::run(marks::MARK_ARCHIVE_SQL)?;
+    Ok(())
+}
+
+#[pg_extern]
+fn current_run_id() -> Result<Option<i64>, spi::Error> {
+    Spi::get_one::<i64>("SELECT mark_run_id()")
+}
+
+/// Opens a new run, closing the previous one if it was never ended.
+#[pg_extern]
+fn begin_run(label: &str, config: default!(JsonB, "'{}'")) -> Result<i64, spi::Error> {
+    end_run()?;
+    let run_id = Spi::get_one_with_args::<i64>(
+        "INSERT INTO BENCHMARK_RUNS (label, config) VALUES ($1, $2) RETURNING run_id",
+        &[label.into(), config.into()],
+    )?;
+    Ok(run_id.expect("INSERT ... RETURNING yields the new run_id"))
+}
+
+#[pg_extern]
+fn end_run() -> Result<Option<i64>, spi::Error> {
+    let run_id = Spi::get_one::<i64>(
+        "UPDATE BENCHMARK_RUNS SET ended_at = clock_timestamp() WHERE ended_at IS NULL RETURNING run_id",
+    );
+    match run_id {
+        Err(spi::Error::InvalidPosition) => Ok(None),
+        other => other,
+    }
+}
+
+/// Transactions per run, transaction type and final mark status.
+#[pg_extern]
+fn run_summary(
+    run_id: default!(Option<i64>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(run_id, i64),
+            name!(label, String),
+            name!(transaction_type, String),
+            name!(final_status, String),
+            name!(transactions, i64),
+        ),
+    >,
+    spi::Error,
+> {
+    let runs = Spi::connect(|client| {
+        let mut runs = Vec::new();
+        for row in client.select(
+            "SELECT run_id, label FROM BENCHMARK_RUNS WHERE $1::BIGINT IS NULL OR run_id = $1 ORDER BY run_id",
+            None,
+            &[run_id.into()],
+        )? {
+            runs.push((
+                row.get_by_name::<i64, _>("run_id")?.unwrap_or_default(),
+                row.get_by_name::<String, _>("label")?.unwrap_or_default(),
+            ));
+        }
+        Ok::<_, spi::Error>(runs)
+    })?;
+
+    let mut rows = Vec::new();
+    for (run_id, label) in runs {
+        let mut outcomes: BTreeMap<(String, String), i64> = BTreeMap::new();
+        for txn in load_transactions(Some(run_id))? {
+            *outcomes.entry((txn.transaction_type, txn.final_status)).or_default() += 1;
+        }
+        for ((transaction_type, final_status), transactions) in outcomes {
+            rows.push((run_id, label.clone(), transaction_type, final_status, transactions));
+        }
+    }
+    Ok(TableIterator::new(rows))
+}
+
+/// Purges every mark stamped with `run_id`, together with the run itself.
+#[pg_extern]
+fn purge_run_marks(run_id: i64, archive: default!(bool, false)) -> Result<i64, spi::Error> {
+    let mut purged = 0;
+    for table in MARK_TABLES {
+        purged += marks::purge_marks_from(table, None, None, Some(run_id), archive)?;
+    }
+    Spi::run_with_args("DELETE FROM BENCHMARK_RUNS WHERE run_id = $1", &[run_id.into()])?;
+    Ok(purged)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::TransactionState;
+    use pgrx::prelude::*;
+
+    // Mark tables as the service extensions create them, minus what the tracker ignores
+    fn setup_tracker() -> Result<(), spi::Error> {
+        for table in crate::MARK_TABLES {
+            let actor_column = if table == "CHECKOUT" { "customer_id" } else { "seller_id" };
+            Spi::run(&format!(r#"
+                CREATE TABLE {table} (
+                    stream_id TEXT,
+                    instance_id TEXT NOT NULL,
+                    transaction_type TEXT NOT NULL,
+                    {actor_column} TEXT NOT NULL,
+                    mark_status TEXT NOT NULL,
+                    db TEXT NOT NULL,
+                    reason TEXT
+                );
+            "#))?;
+        }
+        Spi::run("CREATE TABLE STOCK_RESERVATIONS (instance_id TEXT NOT NULL, customer_id INT NOT NULL)")?;
+        crate::setup_transaction_tracker()
+    }
+
+    fn mark(instance_id: &str, db: &str, mark_status: &str) -> Result<(), spi::Error> {
+        Spi::run_with_args(
+            "INSERT INTO CHECKOUT VALUES ('s', $1, 'CUSTOMER_SESSION', '7', $3, $2)",
+            &[instance_id.into(), db.into(), mark_status.into()],
+        )
+    }
+
+    fn reserve(instance_id: &str) -> Result<(), spi::Error> {
+        Spi::run_with_args("INSERT INTO STOCK_RESERVATIONS VALUES ($1, 7)", &[instance_id.into()])
+    }
+
+    fn state(instance_id: &str) -> Result<Option<String>, spi::Error> {
+        Spi::get_one_with_args(
+            "SELECT state FROM TRANSACTION_TRACKER WHERE instance_id = $1",
+            &[instance_id.into()],
+        )
+    }
+
+    #[pg_test]
+    fn derive_waits_for_every_participant() {
+        let expected = vec!["cart".to_string(), "stock".to_string()];
+        let cart = vec!["cart".to_string()];
+        let both = vec!["stock".to_string(), "cart".to_string()];
+        assert!(TransactionState::derive(&expected, &cart, false) == TransactionState::InProgress);
+        assert!(TransactionState::derive(&expected, &both, false) == TransactionState::Completed);
+        assert!(TransactionState::derive(&expected, &both, true) == TransactionState::Aborted);
+        assert!(TransactionState::derive(&[], &cart, false) == TransactionState::Completed);
+        assert!(TransactionState::derive(&[], &[], false) == TransactionState::InProgress);
+    }
+
+    #[pg_test]
+    fn checkout_completes_on_shipment_success() -> Result<(), spi::Error> {
+        setup_tracker()?;
+        // payment's SUCCESS is not awaited, shipment's is
+        mark("i-1", "payment", "SUCCESS")?;
+        assert_eq!(state("i-1")?.as_deref(), Some("in_progress"));
+        mark("i-1", "shipment", "SUCCESS")?;
+        assert_eq!(state("i-1")?.as_deref(), Some("completed"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn reservation_opens_checkout_before_any_mark() -> Result<(), spi::Error> {
+        setup_tracker()?;
+        reserve("i-1")?;
+        reserve("i-1")?;
+        assert_eq!(state("i-1")?.as_deref(), Some("in_progress"));
+
+        let missing = Spi::get_one::<Vec<String>>("SELECT missing FROM transaction_states(instance_id => 'i-1')")?;
+        assert_eq!(missing, Some(vec!["shipment".to_string()]));
+
+        mark("i-1", "shipment", "SUCCESS")?;
+        assert_eq!(state("i-1")?.as_deref(), Some("completed"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn checkout_stalled_after_reservation_times_out() -> Result<(), spi::Error> {
+        setup_tracker()?;
+        crate::setup_transaction_timeout(0.0, 5.0)?;
+        // Stock reserved, then PaymentMS died: no mark was ever written
+        reserve("i-1")?;
+        reserve("i-2")?;
+        mark("i-2", "shipment", "SUCCESS")?;
+
+        assert_eq!(crate::abort_stalled_transactions()?, (1, 5.0));
+        assert_eq!(state("i-1")?.as_deref(), Some("aborted"));
+        assert_eq!(state("i-2")?.as_deref(), Some("completed"));
+
+        let (stream_id, customer_id) = Spi::get_two::<String, String>(
+            "SELECT stream_id, customer_id FROM CHECKOUT WHERE instance_id = 'i-1' AND mark_status = 'ABORT' AND db = 'timeout'",
+        )?;
+        assert_eq!(stream_id.as_deref(), Some("TransactionMark_CUSTOMER_SESSION"));
+        assert_eq!(customer_id.as_deref(), Some("7"));
+        let reason = Spi::get_one::<String>("SELECT reason FROM CHECKOUT WHERE instance_id = 'i-1'")?;
+        assert_eq!(reason.as_deref(), Some("timed out after 0s waiting for: shipment"));
+        Ok(())
+    }
+
+    #[pg_test]
+    fn failure_mark_aborts_for_good() -> Result<(), spi::Error> {
+        setup_tracker()?;
+        mark("i-1", "stock", "NOT_ACCEPTED")?;
+        mark("i-1", "shipment", "SUCCESS")?;
+
+        assert_eq!(state("i-1")?.as_deref(), Some("aborted"));
+        let failed_by = Spi::get_one::<String>("SELECT failed_by FROM TRANSACTION_TRACKER WHERE instance_id = 'i-1'")?;
+        assert_eq!(failed_by.as_deref(), Some("stock"));
+        Ok(())
+    }
+}
+
+/// This module is required by `cargo pgrx test` invocations.
+/// It must be visible at the root of your extension crate.
+#[cfg(test)]
+pub mod pg_test {
+    pub fn setup(_options: Vec<&str>) {
+        // perform one-off initialization when the pg_test framework starts
+    }
+
+    #[must_use]
+    pub fn postgresql_conf_options() -> Vec<&'static str> {
+        // return any postgresql.conf settings that are required for your tests
+        vec![]
+    }
+}